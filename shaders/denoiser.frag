@@ -137,7 +137,7 @@ void main() {
     vec2 s = vec2((gl_FragCoord.x) - resolution.x/2.0f, (resolution.y - gl_FragCoord.y) - resolution.y/2.0f);
 	vec3 raypos = (camera_matrix * vec4(0, 0, 0, 1)).xyz; 
 	vec3 raydir = normalize(vec3(s.x/resolution.y, focal_length, s.y/resolution.y));
-	raydir = (camera_matrix * vec4(raydir, 0.0)).xyz;
+	raydir = normalize((camera_matrix * vec4(raydir, 0.0)).xyz);
     vec3 worldSpacePosition = raypos + raydir * renderedFrameDepth;
 
     // Then transform that world space position into a camera space position for the last frame
@@ -1,6 +1,7 @@
 #version 450
 
 layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_color;
 
 layout(location=0) out vec4 f_color;
 
@@ -8,6 +9,6 @@ layout(set = 1, binding = 0) uniform texture2D t_diffuse;
 layout(set = 1, binding = 1) uniform sampler s_diffuse;
 
 void main() {
-    f_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords);
+    f_color = texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords) * vec4(v_color, 1.0);
     // f_color = vec4(1.0);
 }
\ No newline at end of file
@@ -1,36 +1,121 @@
 #version 460
 #extension GL_EXT_samplerless_texture_functions : require
 #extension GL_EXT_scalar_block_layout : require
-#extension GL_KHR_shader_subgroup_vote : require
 
 layout(location = 0) out vec4 outColor;
 layout(location = 1) out vec4 outDepth;
 layout(location = 2) out vec4 outAlbedo;
 
+// Mirrors GpuMaterial in world_streamer/uniforms.rs
+struct Material {
+	float roughness;
+	float metalness;
+	float ior;
+	float transmission; // The chance light passes into the voxel instead of reflecting off of it
+	float emission; // Scales the voxel color into the light it gives off
+	float density; // The extinction per voxel of smoke and clouds, zero for surfaces
+};
+
+// Mirrors GpuLight in raytracer/lights.rs, everything is in voxel space
+struct Light {
+	vec3 position;
+	int kind; // One of the LIGHT defines
+	vec3 direction; // Where spot and area lights shine
+	float cos_angle; // The cosine of the half angle of a spot light cone
+	vec3 color; // Premultiplied by the intensity
+	vec2 size; // The edge lengths of an area light
+};
+
+// Mirrors Lighting in raytracer/uniforms.rs
+struct Lighting {
+	vec3 sun_direction; // Normalized, points towards the sun
+	float sun_sharpness;
+	vec3 sun_color;
+	float sun_power;
+	vec3 sky_color;
+	float sky_power;
+	int sky_model; // One of the SKY_MODEL defines
+	float sky_turbidity;
+	float sky_exposure; // Scales the luminance of the preetham sky, which is in kcd/m²
+	float environment_intensity;
+	float environment_rotation; // In radians around the up axis
+};
+
+// Mirrors Fog in raytracer/uniforms.rs, in voxel space
+struct Fog {
+	vec3 albedo;
+	float density; // The extinction per voxel at the fog height, zero turns the fog off
+	float height;
+	float height_falloff; // Zero makes the fog homogeneous
+	float anisotropy; // The henyey-greenstein g of the fog and of smoke voxels
+};
+
 layout(set = 1, binding = 0, std430) uniform Raytrace {
 	mat4 world_matrix;
-    ivec3 scene_size;
     ivec2 resolution;
 	int samples;
 	int primary_ray_only;
     int frame_count;
 	int max_steps;
+	int max_bounces; // How many times a path scatters off of surfaces
+	int roulette_depth; // Paths past this many bounces are ended by russian roulette
     int octree_depth;
     float focal_length;
+	int acceleration; // ACCELERATION_OCTREE or ACCELERATION_DISTANCE_FIELD
+	int brick_level; // A brick of the distance field is as large as a texel of this mip level
+	int debug_view; // One of the DEBUG_VIEW defines
+	int gi_mode; // One of the GI_MODE defines
+	int light_count; // How many entries of lights are in use
+	Lighting lighting;
+	Fog fog;
 };
 
 layout(set = 0, binding = 0) uniform texture3D scene_texture;
 layout(set = 0, binding = 1) uniform texture2D noise_texture;
+layout(set = 0, binding = 2) uniform utexture3D chunk_indirection; // The pool slot plus one of every chunk in the window
+layout(set = 0, binding = 3, std430) uniform Chunks {
+	int chunk_size;
+	ivec3 window_origin; // In chunks
+	ivec3 window_size; // In chunks
+	ivec3 pool_size; // In chunk slots
+};
+layout(set = 0, binding = 4) uniform texture3D dynamic_texture; // Voxelized models, stored in the same slots as the scene
+layout(set = 0, binding = 5) uniform utexture3D distance_field; // Chebyshev distance in bricks to the closest filled brick
+layout(set = 0, binding = 6) uniform texture2D environment_texture; // Equirectangular, linear HDR color
+layout(set = 0, binding = 7, std430) readonly buffer Materials {
+	Material materials[]; // Indexed by material_ids
+};
+layout(set = 0, binding = 8, std430) readonly buffer Lights {
+	Light lights[];
+};
+layout(set = 0, binding = 9) uniform utexture3D material_ids; // The material of every scene voxel, in the same slots as the scene
+
+#define LIGHT_POINT 0
+#define LIGHT_SPOT 1
+#define LIGHT_AREA 2
+
+// The order matches SKY_MODELS in raytracer/mod.rs
+#define SKY_MODEL_COLOR 0
+#define SKY_MODEL_ENVIRONMENT_MAP 1
+#define SKY_MODEL_PREETHAM 2
+
+// The order matches GI_MODES in raytracer/mod.rs
+#define GI_MODE_PATH_TRACING 0
+#define GI_MODE_CONE_TRACING 1
+
+#define ACCELERATION_OCTREE 0
+#define ACCELERATION_DISTANCE_FIELD 1
+
+// The order matches DEBUG_VIEWS in raytracer/mod.rs, every view but the final image skips the denoiser
+#define DEBUG_VIEW_FINAL 0
+#define DEBUG_VIEW_ALBEDO 1
+#define DEBUG_VIEW_NORMALS 2
+#define DEBUG_VIEW_DEPTH 3
+#define DEBUG_VIEW_STEP_HEATMAP 4
+#define DEBUG_VIEW_OCTREE_HEATMAP 5
+#define DEBUG_VIEW_PRIMARY_RAY_ONLY 6
+#define DEBUG_VIEW_DENOISER_INPUT 7
 
-
-#define SKYCOLOR vec3(0.1)
-#define SUNCOLOR vec3(1, 1, 1)
-#define LIGHTCOLOR vec3(5, 0, 0)
-// #define LIGHTDIR normalize(vec3(sin(float(frame_count)/360), cos(float(frame_count)/360), 1.0))
-#define LIGHTDIR normalize(vec3(0.5, 0.5, 1.0))
-#define SUNSHARPNESS 2
-#define SUNPOWER 4.0
-#define SKYPOWER 2.0
 #define SUNLIGHTSTRENGTH 1.0
 
 #define PI 3.1415926535897932384626433832795
@@ -101,6 +186,65 @@ vec3 cosWeightedRandomHemisphereDirection(const vec3 n) {
     return normalize( rr );
 }
 
+// Sample a microfacet normal around n from the GGX distribution, alpha is the squared roughness
+vec3 sampleGGX(vec3 n, float alpha, vec2 r) {
+	vec3 uu = normalize(cross(n, vec3(0.0, 1.0, 1.0)));
+	vec3 vv = cross(uu, n);
+
+	float cosTheta = sqrt((1.0 - r.y) / (1.0 + (alpha * alpha - 1.0) * r.y));
+	float sinTheta = sqrt(1.0 - cosTheta * cosTheta);
+	float phi = 2.0 * PI * r.x;
+
+	return normalize(sinTheta * cos(phi) * uu + sinTheta * sin(phi) * vv + cosTheta * n);
+}
+
+// The GGX brdf times the cosine over the chance of sampling l with sampleGGX, without the fresnel term
+float ggxWeight(vec3 v, vec3 l, vec3 n, vec3 h, float alpha) {
+	float NdotV = max(dot(n, v), 0.0001);
+	float NdotL = max(dot(n, l), 0.0);
+	float k = alpha / 2.0;
+	float G = NdotV / (NdotV * (1.0 - k) + k) * NdotL / (NdotL * (1.0 - k) + k);
+
+	return G * max(dot(v, h), 0.0) / (NdotV * max(dot(n, h), 0.0001));
+}
+
+// The share of light a dielectric reflects, eta is the ratio of the index of refraction the light comes from to the
+// one it goes into
+float dielectricFresnel(float cosI, float eta) {
+	float sinT2 = eta * eta * (1.0 - cosI * cosI);
+
+	if (sinT2 >= 1.0) {
+		return 1.0; // Total internal reflection
+	}
+
+	float f0 = (eta - 1.0) * (eta - 1.0) / ((eta + 1.0) * (eta + 1.0));
+	float c = eta > 1.0 ? sqrt(1.0 - sinT2) : cosI;
+	return f0 + (1.0 - f0) * pow(1.0 - c, 5.0);
+}
+
+// The henyey-greenstein phase function, cosTheta is the cosine of the angle the light is turned by
+float henyeyGreenstein(float cosTheta, float g) {
+	float denominator = 1.0 + g * g - 2.0 * g * cosTheta;
+	return (1.0 - g * g) / (4.0 * PI * denominator * sqrt(denominator));
+}
+
+// Sample the direction light travelling along dir is scattered into from the henyey-greenstein phase function
+vec3 sampleHenyeyGreenstein(vec3 dir, float g, vec2 r) {
+	float cosTheta = 1.0 - 2.0 * r.x;
+
+	if (abs(g) > 0.001) {
+		float s = (1.0 - g * g) / (1.0 - g + 2.0 * g * r.x);
+		cosTheta = (1.0 + g * g - s * s) / (2.0 * g);
+	}
+
+	vec3 uu = normalize(cross(dir, abs(dir.z) < 0.999 ? vec3(0, 0, 1) : vec3(1, 0, 0)));
+	vec3 vv = cross(dir, uu);
+	float sinTheta = sqrt(max(1.0 - cosTheta * cosTheta, 0.0));
+	float phi = 2.0 * PI * r.y;
+
+	return sinTheta * cos(phi) * uu + sinTheta * sin(phi) * vv + cosTheta * dir;
+}
+
 // Scatter a ray with respect to lambertian shading
 vec3 scatter(vec3 n) {
 	vec3 dr = random_in_unit_sphere();
@@ -129,14 +273,253 @@ bool insideBoundingBox(vec3 p, vec3 min, vec3 max) {
 	return p.x > min.x && p.x < max.x && p.y > min.y && p.y < max.y && p.z > min.z && p.z < max.z;
 }
 
-// Is the voxel at the given position and mipmap level filled?
+// Find where the voxel at the given position and mipmap level is stored in the chunk pool, returns false if its chunk isn't resident
+bool poolTexel(ivec3 c, int l, out ivec3 texel) {
+	int shift = findLSB(chunk_size) - l; // The size of a chunk at this mipmap level as a power of two
+	ivec3 window = (c >> shift) - window_origin;
+
+	if (any(lessThan(window, ivec3(0))) || any(greaterThanEqual(window, window_size))) {
+		return false;
+	}
+
+	int slot = int(texelFetch(chunk_indirection, window, 0).r) - 1;
+
+	if (slot < 0) {
+		return false;
+	}
+
+	ivec3 slotPosition = ivec3(slot % pool_size.x, slot / pool_size.x % pool_size.y, slot / (pool_size.x * pool_size.y));
+	texel = (slotPosition << shift) + (c & ((1 << shift) - 1));
+	return true;
+}
+
+// Is the voxel at the given position and mipmap level filled in either layer?
 bool getVoxel(ivec3 c, int l) {
-	return texelFetch(scene_texture, c, l).a != 0;
+	ivec3 texel;
+	return poolTexel(c, l, texel) && (texelFetch(scene_texture, texel, l).a != 0 || texelFetch(dynamic_texture, texel, l).a != 0);
 }
 
-// Get the color of the voxel at a given position and mipmap level
+// Get the color of the voxel at a given position and mipmap level, models are drawn over the scene
 vec3 getColor(ivec3 c, int l) {
-	return texelFetch(scene_texture, clamp(c, ivec3(0), scene_size), l).rgb;
+	ivec3 texel;
+
+	if (!poolTexel(c, l, texel)) {
+		return vec3(0);
+	}
+
+	vec4 dynamic = texelFetch(dynamic_texture, texel, l);
+	return dynamic.a != 0 ? dynamic.rgb : texelFetch(scene_texture, texel, l).rgb;
+}
+
+// Get the material of the voxel at a given position, models use the first material
+Material getMaterial(ivec3 c) {
+	ivec3 texel;
+
+	if (!poolTexel(c, 0, texel) || texelFetch(dynamic_texture, texel, 0).a != 0) {
+		return materials[0];
+	}
+
+	return materials[texelFetch(material_ids, texel, 0).r];
+}
+
+// How much light gets from a point to the given distance along a direction, walks the voxels without the octree.
+// Surfaces block the light and smoke lets through what its density doesn't absorb. The voxel the point lies in only
+// counts when it's smoke, so points just off of a surface aren't shadowed by it.
+float transmittance(vec3 origin, vec3 dir, float maxDist) {
+	ivec3 c = ivec3(floor(origin));
+	vec3 deltaDist = abs(vec3(1) / dir);
+	ivec3 step = ivec3(sign(dir));
+	vec3 sideDist = abs((vec3(c) + vec3(greaterThan(dir, vec3(0))) - origin) * deltaDist);
+
+	float density = getVoxel(c, 0) ? getMaterial(c).density : 0.0; // The density of the current voxel
+	float entered = 0.0; // How far along the ray the current voxel starts
+	float through = 1.0;
+
+	for (int i = 0; i < max_steps; i++) {
+		float exited = min(sideDist.x, min(sideDist.y, sideDist.z));
+		through *= exp(-density * (min(exited, maxDist) - entered));
+
+		if (through < 0.001) {
+			return 0.0;
+		}
+
+		if (exited >= maxDist) {
+			return through;
+		}
+
+		if (any(lessThan(c, window_origin * chunk_size)) || any(greaterThanEqual(c, (window_origin + window_size) * chunk_size))) {
+			return through; // Nothing outside of the window casts shadows
+		}
+
+		bvec3 mask = lessThanEqual(sideDist.xyz, min(sideDist.yzx, sideDist.zxy));
+		sideDist += vec3(mask) * deltaDist;
+		c += ivec3(mask) * step;
+		entered = exited;
+		density = 0.0;
+
+		if (getVoxel(c, 0)) {
+			density = getMaterial(c).density;
+
+			if (density <= 0.0) {
+				return 0.0;
+			}
+		}
+	}
+
+	return through;
+}
+
+// The light one randomly picked light source sends to a diffuse surface at p facing n, before the albedo is applied.
+// Lights inside of surfaces are always occluded, smoke around them only dims them.
+vec3 sampleLights(vec3 p, vec3 n) {
+	if (light_count == 0) {
+		return vec3(0);
+	}
+
+	vec3 r = rand3(g_seed);
+	Light light = lights[min(int(r.z * light_count), light_count - 1)];
+	vec3 target = light.position;
+
+	if (light.kind == LIGHT_AREA) {
+		vec3 uu = normalize(cross(light.direction, abs(light.direction.z) < 0.999 ? vec3(0, 0, 1) : vec3(1, 0, 0)));
+		vec3 vv = cross(light.direction, uu);
+		target += (r.x - 0.5) * light.size.x * uu + (r.y - 0.5) * light.size.y * vv;
+	}
+
+	vec3 toLight = target - p;
+	float dist = length(toLight);
+	vec3 l = toLight / dist;
+	float cosSurface = dot(n, l);
+	float falloff = 1.0 / (dist * dist);
+
+	if (light.kind == LIGHT_SPOT) {
+		falloff *= smoothstep(light.cos_angle, mix(light.cos_angle, 1.0, 0.1), dot(-l, light.direction));
+	} else if (light.kind == LIGHT_AREA) {
+		falloff *= max(dot(-l, light.direction), 0.0) * light.size.x * light.size.y;
+	}
+
+	if (cosSurface <= 0.0 || falloff <= 0.0) {
+		return vec3(0);
+	}
+
+	float visibility = transmittance(p, l, dist);
+
+	if (visibility <= 0.0) {
+		return vec3(0);
+	}
+
+	// The lambertian brdf is the albedo over pi, dividing by the chance of picking this light multiplies by the count
+	return light.color * falloff * visibility * cosSurface / PI * float(light_count);
+}
+
+// The light arriving along a ray that left the scene
+vec3 sunLight(vec3 raydir) {
+	return lighting.sun_color * pow(max(dot(lighting.sun_direction, raydir), 0.0), lighting.sun_sharpness) * lighting.sun_power;
+}
+
+// The sunlight a participating medium at p scatters along dir, the whole lobe of sunLight arrives from the sun direction
+vec3 sunScattering(vec3 p, vec3 dir) {
+	float visibility = transmittance(p, lighting.sun_direction, 1e30);
+
+	if (visibility <= 0.0) {
+		return vec3(0);
+	}
+
+	vec3 lobe = lighting.sun_color * lighting.sun_power * 2.0 * PI / (lighting.sun_sharpness + 1.0);
+	return lobe * visibility * henyeyGreenstein(dot(dir, lighting.sun_direction), fog.anisotropy);
+}
+
+// How far along a ray from p the height fog scatters it, the density falls off exponentially with the height
+float sampleFogDistance(vec3 p, vec3 dir) {
+	if (fog.density <= 0.0) {
+		return 1e30;
+	}
+
+	float opticalDepth = -log(1.0 - rand2(g_seed).x);
+	float density = fog.density * exp(min(-fog.height_falloff * (p.z - fog.height), 30.0)); // The density at p
+	float falloff = fog.height_falloff * dir.z;
+
+	if (abs(falloff) < 0.00001) {
+		return opticalDepth / density;
+	}
+
+	// Invert the optical depth density * (1 - exp(-falloff * t)) / falloff, the ray can leave the fog before reaching it
+	float x = 1.0 - opticalDepth * falloff / density;
+	return x > 0.0 ? -log(x) / falloff : 1e30;
+}
+
+// Rgba32Float textures can't be filtered, so the environment map is interpolated by hand
+vec3 environmentTexel(vec2 uv) {
+	ivec2 size = textureSize(environment_texture, 0);
+	vec2 p = uv * vec2(size) - 0.5;
+	ivec2 i = ivec2(floor(p));
+	vec2 f = p - vec2(i);
+
+	// Longitude wraps around, latitude stops at the poles
+	ivec2 x = (ivec2(i.x, i.x + 1) % size.x + size.x) % size.x;
+	ivec2 y = clamp(ivec2(i.y, i.y + 1), 0, size.y - 1);
+
+	vec3 top = mix(texelFetch(environment_texture, ivec2(x.x, y.x), 0).rgb, texelFetch(environment_texture, ivec2(x.y, y.x), 0).rgb, f.x);
+	vec3 bottom = mix(texelFetch(environment_texture, ivec2(x.x, y.y), 0).rgb, texelFetch(environment_texture, ivec2(x.y, y.y), 0).rgb, f.x);
+	return mix(top, bottom, f.y);
+}
+
+// The Perez luminance distribution, theta is the angle of the view to the zenith and gamma the angle to the sun
+float perez(float theta, float gamma, float A, float B, float C, float D, float E) {
+	return (1.0 + A * exp(B / max(cos(theta), 0.01))) * (1.0 + C * exp(D * gamma) + E * cos(gamma) * cos(gamma));
+}
+
+// A Practical Analytic Model for Daylight, Preetham et al. 1999
+vec3 preethamSky(vec3 raydir) {
+	float T = lighting.sky_turbidity;
+	vec3 sunDirection = lighting.sun_direction;
+
+	// The model doesn't cover the sun below the horizon, it is held at the horizon and faded out instead
+	float thetaS = acos(clamp(sunDirection.z, 0.0, 1.0));
+	float theta = acos(clamp(raydir.z, 0.0, 1.0));
+	float gamma = acos(clamp(dot(raydir, sunDirection), -1.0, 1.0));
+
+	float chi = (4.0 / 9.0 - T / 120.0) * (PI - 2.0 * thetaS);
+	float zenithY = (4.0453 * T - 4.9710) * tan(chi) - 0.2155 * T + 2.4192;
+
+	vec3 thetaS3 = vec3(thetaS * thetaS * thetaS, thetaS * thetaS, thetaS);
+	float zenithX = dot(vec3(0.00166, -0.00375, 0.00209), thetaS3) * T * T
+		+ (dot(vec3(-0.02903, 0.06377, -0.03202), thetaS3) + 0.00394) * T
+		+ dot(vec3(0.11693, -0.21196, 0.06052), thetaS3) + 0.25886;
+	float zenithy = dot(vec3(0.00275, -0.00610, 0.00317), thetaS3) * T * T
+		+ (dot(vec3(-0.04214, 0.08970, -0.04153), thetaS3) + 0.00516) * T
+		+ dot(vec3(0.15346, -0.26756, 0.06670), thetaS3) + 0.26688;
+
+	float Y = zenithY
+		* perez(theta, gamma, 0.1787 * T - 1.4630, -0.3554 * T + 0.4275, -0.0227 * T + 5.3251, 0.1206 * T - 2.5771, -0.0670 * T + 0.3703)
+		/ perez(0.0, thetaS, 0.1787 * T - 1.4630, -0.3554 * T + 0.4275, -0.0227 * T + 5.3251, 0.1206 * T - 2.5771, -0.0670 * T + 0.3703);
+	float x = zenithX
+		* perez(theta, gamma, -0.0193 * T - 0.2592, -0.0665 * T + 0.0008, -0.0004 * T + 0.2125, -0.0641 * T - 0.8989, -0.0033 * T + 0.0452)
+		/ perez(0.0, thetaS, -0.0193 * T - 0.2592, -0.0665 * T + 0.0008, -0.0004 * T + 0.2125, -0.0641 * T - 0.8989, -0.0033 * T + 0.0452);
+	float y = zenithy
+		* perez(theta, gamma, -0.0167 * T - 0.2608, -0.0950 * T + 0.0092, -0.0079 * T + 0.2102, -0.0441 * T - 1.6537, -0.0109 * T + 0.0529)
+		/ perez(0.0, thetaS, -0.0167 * T - 0.2608, -0.0950 * T + 0.0092, -0.0079 * T + 0.2102, -0.0441 * T - 1.6537, -0.0109 * T + 0.0529);
+
+	// xyY to XYZ to linear sRGB
+	vec3 XYZ = vec3(x * Y / y, Y, (1.0 - x - y) * Y / y);
+	vec3 rgb = mat3(3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570) * XYZ;
+
+	return max(rgb, vec3(0)) * lighting.sky_exposure * smoothstep(-0.1, 0.05, sunDirection.z);
+}
+
+vec3 skyLight(vec3 raydir) {
+	if (lighting.sky_model == SKY_MODEL_PREETHAM) {
+		return preethamSky(raydir);
+	}
+
+	if (lighting.sky_model == SKY_MODEL_ENVIRONMENT_MAP) {
+		// z is up, the top row of the image is straight up
+		float longitude = atan(raydir.y, raydir.x) + lighting.environment_rotation;
+		float latitude = acos(clamp(raydir.z, -1.0, 1.0));
+		return environmentTexel(vec2(fract(longitude / (2.0 * PI)), latitude / PI)) * lighting.environment_intensity;
+	}
+
+	return lighting.sky_color * lighting.sky_power;
 }
 
 struct Hit {
@@ -146,6 +529,21 @@ struct Hit {
 };
 
 float primary_dist = 0;
+int trace_steps = 0; // The steps taken by the last call to trace
+float trace_level = 0; // The average octree level the steps of the last call to trace were taken at
+bool trace_smoke = false; // Did the last call to trace pass through smoke on its way to the primary hit
+
+// The distance in bricks from the brick holding the voxel to the closest filled brick, zero outside of the window
+int brickDistance(ivec3 c) {
+	ivec3 brick = (c >> brick_level) - (window_origin << (findLSB(chunk_size) - brick_level));
+	ivec3 size = textureSize(distance_field, 0);
+
+	if (any(lessThan(brick, ivec3(0))) || any(greaterThanEqual(brick, size))) {
+		return 0;
+	}
+
+	return int(texelFetch(distance_field, brick, 0).r);
+}
 
 // The main raytracing function, the alpha channel of the vec4 that is returned is the depth
 Hit trace(vec3 raydir, vec3 raypos, bool primary) {
@@ -153,18 +551,25 @@ Hit trace(vec3 raydir, vec3 raypos, bool primary) {
 	vec3 n;
 	vec2 res = vec2(0);
 
-	if(!(insideBoundingBox(raypos, vec3(0), vec3(scene_size)))) {
-		if(rayAABB(raypos, raydir, vec3(0, 0, 0), vec3(scene_size), res, n)) {
+	// Only the window of chunks streamed in around the player can be traced
+	vec3 worldMin = vec3(window_origin * chunk_size);
+	vec3 worldMax = vec3((window_origin + window_size) * chunk_size);
+
+	if(!(insideBoundingBox(raypos, worldMin, worldMax))) {
+		if(rayAABB(raypos, raydir, worldMin, worldMax, res, n)) {
 			raypos += raydir * res.x + n * 0.00001;
 		} else {
-			return Hit(SUNCOLOR * pow(max(dot(normalize(LIGHTDIR), raydir), 0.0), SUNSHARPNESS) * SUNPOWER + SKYCOLOR * SKYPOWER, 0.0, vec3(0)); // Return fully lit scene
+			return Hit(sunLight(raydir) + skyLight(raydir), 0.0, vec3(0)); // Return fully lit scene
 		}
 	}
 
-	int maxLevel = 0/*octree_depth-1*/;
-	int level = maxLevel/2; // The current level in the octree
+	// Levels above the chunk size would mix the chunks stored next to each other in the pool, the mipmapper never builds those
+	int topLevel = min(findLSB(chunk_size), textureQueryLevels(scene_texture) - 1);
+	int maxLevel = acceleration == ACCELERATION_OCTREE ? clamp(octree_depth - 1, 0, topLevel) : 0;
+	int level = 0; // The current level in the octree
 
 	float complexity = 0; // Used to display a complexity map, however not required for the actual rendering
+	float levelSum = 0; // Used to display the octree level heatmap
 
 	ivec3 gridPosition = ivec3(floor(raypos));
 
@@ -172,11 +577,10 @@ Hit trace(vec3 raydir, vec3 raypos, bool primary) {
     ivec3 step = ivec3(sign(raydir));
 	bvec3 raydirsign = greaterThan(sign(raydir), vec3(0));
 
-	vec3 nextEdge = vec3(gridPosition & ivec3(-1 << level)) + vec3(greaterThan(raydir, vec3(0.0))) * (1 << level);
+	// sideDist holds the distance along the ray from raypos to the next edge of the current cell on every axis
+	vec3 nextEdge = vec3(gridPosition & ivec3(-1 << level)) + vec3(raydirsign) * (1 << level);
 	vec3 sideDist = abs((nextEdge - raypos) * deltaDist);
 
-	bool moved = false;
-
 	float dist = 0;
     vec3 normal = vec3(0.0);
 	int steps = 0;
@@ -184,76 +588,192 @@ Hit trace(vec3 raydir, vec3 raypos, bool primary) {
 	vec3 luminance = vec3(0);
 	vec3 color_out = vec3(1);
 	float depth = 0;
-	float bounces = 0;
-	int min_level = 0;
+	int bounces = 0;
 
 	bool hit = false;
 	bool absorbed = false;
+	bool escaped = false; // Did the ray leave the window, a ray that runs out of steps first doesn't see the sky
+	bool inside = false; // Is the ray travelling through a transmissive voxel
+	vec3 albedo = vec3(1); // The color of the last voxel hit from outside
+	Material medium; // The material of the last voxel hit from outside
+	bool sunSampled = false; // Was the sun sampled directly at the last scattering event
+
+	float fogDistance = primary ? 1e30 : sampleFogDistance(raypos, raydir); // Where along the ray the fog scatters it
+	trace_smoke = false;
 
 	for(int i=0; i<max_steps; i++) { // Begin marching the ray now
-		if(!insideBoundingBox(gridPosition, vec3(-2), scene_size + vec3(1))) { // If we aren't inside the bounding box of the scene, there is no more geometry to intersect and we can return
+		steps = i;
+		levelSum += level;
+
+		if(!insideBoundingBox(gridPosition, worldMin - vec3(2), worldMax + vec3(1))) { // If we aren't inside the bounding box of the scene, there is no more geometry to intersect and we can return
 			// return vec4(vec3(float(i)/float(4)), 1.0);
+			escaped = true;
 			break;
 		}
 
-		bool nonEmpty = getVoxel(gridPosition >> level, level); // Is the current voxel empty
-		bool belowEmpty = !getVoxel(gridPosition >> (level + 1), level + 1) && level < maxLevel; // Can we move upwards an octree level?
-		bool verticalMove = nonEmpty || belowEmpty; // If either we can move down or move up in the octree
+		bool fogged = !inside && dist > fogDistance; // The fog scattered the ray before it reached the current cell
 
-		// min_level = min(level - 1, int(floor((dist + primary_dist)/100)));
+		if (acceleration == ACCELERATION_DISTANCE_FIELD && !inside && !fogged) {
+			int empty = brickDistance(gridPosition) - 1; // Every brick this close on each axis is empty
 
-		if(verticalMove) {
-			complexity += int(nonEmpty); // Increment the complexity variable to keep track of a complexity map
+			if (empty >= 0) {
+				// Jump to where the ray leaves the empty cube of bricks around the current one
+				int brickSize = 1 << brick_level;
+				vec3 emptyMin = vec3(((gridPosition >> brick_level) - empty) * brickSize);
+				vec3 emptyMax = vec3(((gridPosition >> brick_level) + empty + 1) * brickSize);
 
-			vec3 modifiedRayPosition = raypos + raydir * dist;
+				vec3 current = raypos + raydir * dist;
+				vec3 exits = (mix(emptyMin, emptyMax, raydirsign) - current) / raydir;
+				float exitTime = min(exits.x, min(exits.y, exits.z));
+				bvec3 mask = equal(exits, vec3(exitTime));
 
-			if(level < (min_level + 1) && nonEmpty) { // If we are at the lowest level and hit a non empty grid position that means we hit scene geometry and we can scatter the ray off of it
-				// return vec4(vec3(complexity/(maxLevel)), 1); // Return complexity map
+				dist += max(exitTime, 0.0);
+				normal = vec3(mask) * -step;
 
-				color_out *= getColor(gridPosition >> level, level);
-				// outColor *= 0.5; // Disable color and only view lighting
+				// The exit lies on a voxel boundary along the exit axis, flooring it could land on either side
+				vec3 exitVoxel = mix(emptyMin - 1.0, emptyMax, raydirsign);
+				gridPosition = ivec3(mix(floor(raypos + raydir * dist), exitVoxel, mask));
 
-				if(depth == 0) { // Update the depth variable to store the distance to the first intersection with the scene geometry
-					depth = dist; // TODO this seems incorrect?
-					hit = true;
-					// outNormal = vec4(normal, 1.0);
-				}
+				nextEdge = vec3(gridPosition) + vec3(raydirsign);
+				sideDist = abs((nextEdge - raypos) * deltaDist);
+				continue;
+			}
+		}
+
+		bool nonEmpty = !fogged && getVoxel(gridPosition >> level, level); // Is the current cell filled
+
+		// Primary rays look through smoke for the surface behind it, the paths traced from the camera scatter in it
+		bool smoke = primary && nonEmpty && level == 0 && getMaterial(gridPosition).density > 0.0;
+		trace_smoke = trace_smoke || smoke;
+
+		if(fogged || (nonEmpty != inside && level == 0 && !smoke)) { // A filled voxel at the lowest level means we hit scene geometry and we can scatter the ray off of it, inside of a transmissive voxel the first empty one is where the ray leaves it
+			complexity++; // Increment the complexity variable to keep track of a complexity map
+
+			// return vec4(vec3(complexity/(maxLevel)), 1); // Return complexity map
+
+			if(depth == 0 && !fogged) { // Update the depth variable to store the distance to the first intersection with the scene geometry
+				depth = dist; // TODO this seems incorrect?
+				hit = true;
+				// outNormal = vec4(normal, 1.0);
+			}
+
+			if (primary) {
+				trace_steps = steps;
+				trace_level = levelSum / float(steps + 1);
+				return Hit(getColor(gridPosition, 0), depth + res.x, normal);
+			}
 
-				if (primary) {
-					return Hit(getColor(gridPosition >> level, level), depth + res.x, normal);
+			// The voxel left behind decides how the ray scatters when it leaves a transmissive one
+			if (!inside && !fogged) {
+				albedo = getColor(gridPosition, 0);
+				medium = getMaterial(gridPosition);
+				luminance += color_out * albedo * medium.emission;
+			}
+			// outColor *= 0.5; // Disable color and only view lighting
+
+			vec3 hitPosition = raypos + raydir * (fogged ? fogDistance : dist);
+			vec3 side = normal; // Which side of the surface the ray continues on
+			raypos = hitPosition + side * 0.01; // Step off of the scene geometry slightly to avoid getting stuck inside of it
+
+			vec3 view = -raydir;
+			vec3 h = sampleGGX(normal, max(medium.roughness * medium.roughness, 0.001), rand2(g_seed));
+			float viewDotH = max(dot(view, h), 0.0);
+			vec3 lobe = rand3(g_seed);
+
+			// Smoke scatters the ray somewhere inside of the voxel or lets it through
+			float flight = 1e30;
+
+			if (!fogged && !inside && medium.density > 0.0) {
+				vec3 exits = (vec3(gridPosition) + vec3(raydirsign) - hitPosition) / raydir;
+				float through = max(min(exits.x, min(exits.y, exits.z)), 0.0);
+				flight = -log(1.0 - lobe.x) / medium.density;
+
+				if (flight >= through) {
+					fogDistance -= dist + through + 0.001;
+					raypos = hitPosition + raydir * (through + 0.001);
+					dist = 0;
+					gridPosition = ivec3(floor(raypos));
+					nextEdge = vec3(gridPosition) + vec3(raydirsign);
+					sideDist = abs((nextEdge - raypos) * deltaDist);
+					continue;
 				}
+			}
 
-				modifiedRayPosition += normal * 0.01; // Step off of the scene geometry slightly to avoid getting stuck inside of it
-				
-				raypos = modifiedRayPosition; // Update the ray position, ray direction and the values that depend on it
-				raydir = cosWeightedRandomHemisphereDirection(normal);
-				deltaDist = abs(vec3(1)/raydir);
-				step = ivec3(sign(raydir));
-				raydirsign = greaterThan(sign(raydir), vec3(0));
-				dist = 0; // Reset the distance to zero
-				bounces++;
-				if (i > 75 && subgroupAll(hit)) {
-					absorbed = true;
-					break;
+			sunSampled = false;
+
+			if (fogged || flight < 1e30) { // Scatter off of the fog or smoke and sample the sun directly
+				vec3 scatterAlbedo = fogged ? fog.albedo : albedo;
+				raypos = hitPosition + raydir * (fogged ? 0.0 : flight);
+				luminance += color_out * scatterAlbedo * sunScattering(raypos, raydir);
+				color_out *= scatterAlbedo;
+				raydir = sampleHenyeyGreenstein(raydir, fog.anisotropy, rand2(g_seed));
+				side = raydir;
+				sunSampled = true;
+			} else if (inside || lobe.x < medium.transmission) { // Reflect off of or refract through the surface of a dielectric
+				float eta = inside ? medium.ior : 1.0 / medium.ior;
+				vec3 refracted = refract(raydir, h, eta);
+
+				if (refracted == vec3(0) || lobe.y < dielectricFresnel(viewDotH, eta)) {
+					raydir = reflect(raydir, h);
+				} else {
+					raydir = refracted;
+					side = -normal;
+					raypos = hitPosition + side * 0.01;
+					color_out *= inside ? vec3(1) : albedo; // Light is tinted by the color of the glass on the way in
+					inside = !inside;
 				}
+			} else if (lobe.y < medium.metalness || lobe.z < dielectricFresnel(viewDotH, 1.0 / medium.ior)) { // Specular reflection, the lobe was picked with the chance of the fresnel of dielectrics
+				raydir = reflect(raydir, h);
+				vec3 F = lobe.y < medium.metalness ? albedo + (1.0 - albedo) * pow(1.0 - viewDotH, 5.0) : vec3(1);
+				color_out *= F * ggxWeight(view, raydir, normal, h, max(medium.roughness * medium.roughness, 0.001));
+			} else { // Lambertian diffuse reflection
+				color_out *= albedo;
+				luminance += color_out * sampleLights(raypos, normal);
+				raydir = cosWeightedRandomHemisphereDirection(normal);
 			}
 
-			gridPosition = ivec3(floor(modifiedRayPosition - normal * 0.0001)); // Calculate a new grid position given that information
+			if (dot(raydir, side) <= 0.0) { // The sampled microfacet sent the ray into the surface
+				absorbed = true;
+				break;
+			}
 
-			level -= int(nonEmpty); // If we can move down, move down
-			level = max(0, level);
-			level += int(!nonEmpty); // If we can move up, move up
-			
-			// Recalculate the variables dependent on grid position
-			nextEdge = vec3(gridPosition & ivec3(-1 << level)) + vec3(greaterThan(raydir, vec3(0.0))) * (1 << level);
-			sideDist = abs((nextEdge - modifiedRayPosition) * deltaDist);
+			if (bounces == max_bounces) {
+				absorbed = true;
+				break;
+			}
 
-			if(moved) { // Accumulate the distance values
-				sideDist += dist;
+			bounces++;
+
+			// Past the roulette depth paths survive with the chance of the light they carry, survivors make up for the rest
+			if (bounces > roulette_depth) {
+				float survival = min(max(color_out.r, max(color_out.g, color_out.b)), 0.95);
+
+				if (rand2(g_seed).x >= survival) {
+					absorbed = true;
+					break;
+				}
+
+				color_out /= survival;
 			}
-		}
 
-		if(!verticalMove) { // If we aren't moving vertically, move horizontally
+			// Update the values that depend on the ray direction
+			deltaDist = abs(vec3(1)/raydir);
+			step = ivec3(sign(raydir));
+			raydirsign = greaterThan(sign(raydir), vec3(0));
+			dist = 0; // Reset the distance to zero
+			fogDistance = sampleFogDistance(raypos, raydir);
+
+			gridPosition = ivec3(floor(raypos));
+		} else if(nonEmpty && level > 0) { // Move down into the child cell the ray is in, inside of a transmissive voxel the ray stays at the lowest level and steps on through the filled ones
+			complexity++;
+
+			// The ray entered the cell through one of its faces, keep the child inside the cell where flooring lands on a face
+			ivec3 cellMin = gridPosition & ivec3(-1 << level);
+			level--;
+			gridPosition = clamp(ivec3(floor(raypos + raydir * dist)), cellMin, cellMin + (2 << level) - 1);
+		} else if(level < maxLevel && !inside && !getVoxel(gridPosition >> (level + 1), level + 1)) { // Move up when the parent cell is empty too
+			level++;
+		} else { // Otherwise move horizontally to the next cell of this level
 			float minTime = min(sideDist.x, min(sideDist.y, sideDist.z));
 			dist = minTime;
 
@@ -262,23 +782,96 @@ Hit trace(vec3 raydir, vec3 raypos, bool primary) {
 			gridPosition = (gridPosition & ivec3(-1 << level)) + ivec3(mask) * vstep;
 			sideDist += vec3(mask) * deltaDist * vec3(1 << level);
 			normal = vec3(mask) * -step;
-			moved = true;
+			continue;
 		}
 
-		steps = i;
-		// min_level = i/(200/octree_depth);
+		// The cell changed size or the ray changed direction, recalculate the variables dependent on grid position
+		nextEdge = vec3(gridPosition & ivec3(-1 << level)) + vec3(raydirsign) * (1 << level);
+		sideDist = abs((nextEdge - raypos) * deltaDist);
 	}
 
+	trace_steps = steps;
+	trace_level = levelSum / float(steps + 1);
+	absorbed = absorbed || !escaped;
+
 	// return vec4(vec3(float(steps)/max_steps), 1.0); // Return how many steps it took to render this pixel
 	// return vec4(outColor, 1.0); // Return scene lit only using ambient occlusion
 	// return vec4(vec3(complexity/(maxLevel * 4)), 1); // Return complexity map
 	// return vec4(vec3(dist/128), 1); // Return distance map
-	return Hit(color_out * (SUNCOLOR * pow(max(dot(normalize(LIGHTDIR), raydir), 0.0), SUNSHARPNESS) * SUNPOWER + SKYCOLOR * SKYPOWER * float(!absorbed) + luminance), (depth + res.x) * float(hit), normal * float(hit)); // Return fully lit scene
+	// Emission and light sources were gathered at every bounce, a path that wasn't ended early also sees the sky
+	return Hit(luminance + color_out * (sunLight(raydir) * float(!sunSampled) + skyLight(raydir)) * float(!absorbed), (depth + res.x) * float(hit), normal * float(hit)); // Return fully lit scene
 	// return Hit(color_out * float(!absorbed), (depth + res.x) * float(hit), normal * float(hit)); // Return fully lit scene
 	// return Hit(color_out, (depth + res.x) * hit, normal * hit);
 	// return vec4(vec3(raydir.x, raydir.y, 0), 1.0);
 }
 
+// The filled share and color of the cell of the mip chain holding p, models are drawn over the scene
+vec4 mipSample(vec3 p, int l) {
+	ivec3 texel;
+
+	if (!poolTexel(ivec3(floor(p)) >> l, l, texel)) {
+		return vec4(0);
+	}
+
+	vec4 dynamic = texelFetch(dynamic_texture, texel, l);
+	vec4 scene = texelFetch(scene_texture, texel, l);
+	return dynamic.a != 0 ? dynamic : scene;
+}
+
+// March a cone through the mip chain, returns the light reflected by what it passes plus the sky it sees and how much
+// of the cone was blocked
+vec4 coneTrace(vec3 origin, vec3 dir, float tanHalfAngle) {
+	int maxLevel = min(findLSB(chunk_size), textureQueryLevels(scene_texture) - 1);
+	float maxDist = float(max(window_size.x, max(window_size.y, window_size.z)) * chunk_size);
+
+	// The mips only hold colors, so occluders are lit by the sky above them instead of their real surroundings
+	vec3 occluderLight = skyLight(vec3(0, 0, 1));
+	vec3 color = vec3(0);
+	float occlusion = 0;
+	float t = 1;
+
+	for (int i = 0; i < 64 && occlusion < 0.95 && t < maxDist; i++) {
+		float diameter = max(2.0 * t * tanHalfAngle, 1.0);
+		float level = min(log2(diameter), float(maxLevel));
+		int l = int(level);
+
+		vec3 p = origin + dir * t;
+		vec4 voxel = mix(mipSample(p, l), mipSample(p, min(l + 1, maxLevel)), level - float(l));
+
+		// The opacity of a cell is for crossing all of it, correct it for the shorter step
+		float alpha = 1.0 - pow(1.0 - voxel.a, diameter * 0.5 / exp2(level));
+		color += (1.0 - occlusion) * alpha * voxel.rgb * occluderLight;
+		occlusion += (1.0 - occlusion) * alpha;
+		t += diameter * 0.5;
+	}
+
+	return vec4(color + (1.0 - occlusion) * skyLight(dir), occlusion);
+}
+
+// A noise free preview of the lighting at a surface from six cones covering the hemisphere and a narrow one towards the
+// sun, emission and light sources are left out
+vec3 coneTracedLight(vec3 p, vec3 n, vec3 albedo) {
+	vec3 origin = p + n * 0.5;
+	vec3 uu = normalize(cross(n, vec3(0.0, 1.0, 1.0)));
+	vec3 vv = cross(uu, n);
+
+	// One cone along the normal and five around it at 60 degrees, weighted so they integrate the cosine to pi
+	vec3 irradiance = PI / 4.0 * coneTrace(origin, n, 0.577).rgb;
+
+	for (int i = 0; i < 5; i++) {
+		float phi = 2.0 * PI * float(i) / 5.0;
+		vec3 dir = normalize(0.5 * n + 0.866 * (cos(phi) * uu + sin(phi) * vv));
+		irradiance += 3.0 * PI / 20.0 * coneTrace(origin, dir, 0.577).rgb;
+	}
+
+	// The whole lobe of sunLight arrives from the sun direction, as in sunScattering
+	float sunVisibility = 1.0 - coneTrace(origin, lighting.sun_direction, 0.05).a;
+	vec3 sun = lighting.sun_color * lighting.sun_power * 2.0 * PI / (lighting.sun_sharpness + 1.0);
+	irradiance += sun * sunVisibility * max(dot(n, lighting.sun_direction), 0.0);
+
+	return albedo / PI * irradiance;
+}
+
 void mainImage(in vec2 fragCoord )
 {
 	// Initialize global seed for RNG
@@ -295,21 +888,64 @@ void mainImage(in vec2 fragCoord )
 	vec3 raypos = vec3(world_matrix * vec4(0.0, 0.0, 0.0, 1.0));
 	// vec3 raypos = vec3(0.1, 0.0, 0.0);
 	vec3 raydir = normalize(vec3(s.x/resolution.y, focal_length, s.y/resolution.y));
-	raydir = (world_matrix * vec4(raydir, 0.0)).xyz;
+	raydir = normalize((world_matrix * vec4(raydir, 0.0)).xyz); // The world matrix maps into voxel space, which is scaled
 
 	Hit primary = trace(raydir, raypos, true);
 
-	if (primary_ray_only == 1) {
-		outColor = vec4(primary.color, 1.0) * clamp(abs(dot(primary.normal, LIGHTDIR)), 0.5, 1.0);
+	vec3 debugColor = vec3(0);
+	float window = float(max(window_size.x, max(window_size.y, window_size.z)) * chunk_size);
+	float heat = 0;
+
+	switch (debug_view) {
+		case DEBUG_VIEW_ALBEDO:
+			debugColor = primary.color;
+			break;
+		case DEBUG_VIEW_NORMALS:
+			debugColor = primary.normal * 0.5 + 0.5;
+			break;
+		case DEBUG_VIEW_DEPTH:
+			debugColor = vec3(primary.depth / window);
+			break;
+		case DEBUG_VIEW_STEP_HEATMAP:
+			heat = float(trace_steps) / float(max_steps);
+			debugColor = vec3(heat, 1.0 - abs(heat * 2.0 - 1.0), 1.0 - heat);
+			break;
+		case DEBUG_VIEW_OCTREE_HEATMAP:
+			heat = trace_level / float(max(octree_depth - 1, 1));
+			debugColor = vec3(heat, 1.0 - abs(heat * 2.0 - 1.0), 1.0 - heat);
+			break;
+	}
+
+	if (debug_view >= DEBUG_VIEW_ALBEDO && debug_view <= DEBUG_VIEW_OCTREE_HEATMAP) {
+		outColor = vec4(debugColor, 1.0);
+		outDepth = vec4(primary.normal, primary.depth/10000);
+		outAlbedo = outColor;
+		return;
+	}
+
+	if (primary_ray_only == 1 || debug_view == DEBUG_VIEW_PRIMARY_RAY_ONLY) {
+		outColor = vec4(primary.color, 1.0) * clamp(abs(dot(primary.normal, lighting.sun_direction)), 0.5, 1.0);
 		outDepth = vec4(primary.normal, primary.depth/10000);
 		outAlbedo = vec4(primary.color, 1.0);
 		return;
 	}
 
-	raypos += raydir * (primary.depth - 0.01);
+	if (gi_mode == GI_MODE_CONE_TRACING) {
+		vec3 surface = raypos + raydir * primary.depth;
+		outColor = vec4(primary.depth != 0.0 ? coneTracedLight(surface, primary.normal, primary.color) : primary.color, 1.0);
+		outDepth = vec4(primary.normal, primary.depth/10000);
+		outAlbedo = vec4(primary.color, 1.0);
+		return;
+	}
+
+	// Rays start at the primary hit, unless the fog or smoke between it and the camera has to be traced too
+	if (fog.density <= 0.0 && !trace_smoke) {
+		raypos += raydir * (primary.depth - 0.01);
+	}
+
 	primary_dist = primary.depth;
 
-	if (primary.depth != 0.0) {
+	if (primary.depth != 0.0 || fog.density > 0.0 || trace_smoke) {
 	// Render the scenes samples
 		for(int i=0; i < samples; i++) {
 			noise_sample_count += 127;
@@ -1,24 +1,71 @@
 #version 450
+#extension GL_EXT_samplerless_texture_functions : require
+#extension GL_EXT_scalar_block_layout : require
 
 layout(location=0) in vec2 v_tex_coords;
+layout(location=1) in vec3 v_color;
+layout(location=2) in vec3 v_voxel;
+layout(location=3) flat in int v_axis;
 
 layout(location=0) out vec4 f_color;
 
 layout(set=0, binding=0) uniform Camera {
-    uint scene_size;
+    uvec3 scene_size;
     mat4 u_model;
+    uint dominant_axis_only;
 };
 
 layout(set = 1, binding = 0) uniform texture2D t_diffuse;
 layout(set = 1, binding = 1) uniform sampler s_diffuse;
 
 layout(rgba32f, set = 2, binding = 0) uniform writeonly restrict image3D u_voxel_grid;
+layout(set = 2, binding = 1) uniform utexture3D u_chunk_indirection;
+layout(set = 2, binding = 2, std430) uniform Chunks {
+    int chunk_size;
+    ivec3 window_origin;
+    ivec3 window_size;
+    ivec3 pool_size;
+};
+
+// Finds where a voxel is stored in the chunk pool, returns false if its chunk isn't resident. Mirrors poolTexel in
+// raytrace.frag
+bool poolTexel(ivec3 c, out ivec3 texel) {
+    int shift = findLSB(chunk_size);
+    ivec3 window = (c >> shift) - window_origin;
+
+    if (any(lessThan(window, ivec3(0))) || any(greaterThanEqual(window, window_size))) {
+        return false;
+    }
+
+    int slot = int(texelFetch(u_chunk_indirection, window, 0).r) - 1;
+
+    if (slot < 0) {
+        return false;
+    }
+
+    ivec3 slotPosition = ivec3(slot % pool_size.x, slot / pool_size.x % pool_size.y, slot / (pool_size.x * pool_size.y));
+    texel = (slotPosition << shift) + (c & (chunk_size - 1));
+    return true;
+}
 
 void main() {
-    vec4 color = vec4(texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb, 1.0);
-    ivec3 position = ivec3(gl_FragCoord.xzy * vec3(1.0, 512.0, 1.0));
+    vec4 color = vec4(texture(sampler2D(t_diffuse, s_diffuse), v_tex_coords).rgb * v_color, 1.0);
+
+    // The triangle is drawn once along every axis, with conservative rasterization only the projection along the
+    // axis its normal points the most along is kept. Otherwise all three are kept so thin triangles leave no gaps.
+    vec3 normal = abs(cross(dFdx(v_voxel), dFdy(v_voxel)));
+    int dominant = normal.x >= normal.y && normal.x >= normal.z ? 0 : normal.y >= normal.z ? 1 : 2;
+
+    if (dominant_axis_only != 0 && dominant != v_axis) {
+        discard;
+    }
+
+    ivec3 position = ivec3(floor(v_voxel));
+    ivec3 texel;
 
-    imageStore(u_voxel_grid, position, color);
+    if (poolTexel(position, texel)) {
+        imageStore(u_voxel_grid, texel, color);
+    }
     
     f_color = vec4(1.0);
-}
\ No newline at end of file
+}
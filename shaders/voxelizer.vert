@@ -1,17 +1,40 @@
 #version 450
+#extension GL_EXT_scalar_block_layout : require
 
 layout(location=0) in vec3 a_position;
 layout(location=1) in vec2 a_tex_coords;
+layout(location=3) in vec3 a_color;
 
 layout(location=0) out vec2 v_tex_coords;
+layout(location=1) out vec3 v_color;
+layout(location=2) out vec3 v_voxel;
+layout(location=3) flat out int v_axis;
 
 layout(set=0, binding=0) uniform Camera {
-    uint scene_size;
+    uvec3 scene_size;
     mat4 u_model;
+    uint dominant_axis_only;
+};
+
+layout(set = 2, binding = 2, std430) uniform Chunks {
+    int chunk_size;
+    ivec3 window_origin;
+    ivec3 window_size;
+    ivec3 pool_size;
 };
 
 void main() {
-    v_tex_coords = (vec4(a_position, 1.0)).xy;
-    gl_Position = u_model * vec4(a_position, 1.0);
-    // gl_Position = vec4(a_position, 1.0);
-}
\ No newline at end of file
+    v_tex_coords = vec2(a_tex_coords.x, 1.0 - a_tex_coords.y);
+    v_color = a_color;
+
+    // The model matrix maps the model into voxel space
+    v_voxel = (u_model * vec4(a_position, 1.0)).xyz;
+    vec3 local = v_voxel - vec3(window_origin * chunk_size);
+
+    // Every instance projects the window along another axis, the largest dimension of the window fills the target
+    v_axis = gl_InstanceIndex;
+    float size = float(max(scene_size.x, max(scene_size.y, scene_size.z)));
+    vec3 projected = v_axis == 0 ? local.yzx : v_axis == 1 ? local.xzy : local.xyz;
+
+    gl_Position = vec4(projected.xy / size * 2.0 - 1.0, projected.z / float(scene_size[v_axis]), 1.0);
+}
@@ -41,6 +41,16 @@ impl ConfigValue {
             ConfigValue::String(s) => s.parse::<bool>().unwrap(),
         }
     }
+    pub fn as_string(&self) -> String {
+        match self {
+            ConfigValue::Bool(b) => b.to_string(),
+            ConfigValue::I32(i) => i.to_string(),
+            ConfigValue::I64(i) => i.to_string(),
+            ConfigValue::F32(f) => f.to_string(),
+            ConfigValue::F64(f) => f.to_string(),
+            ConfigValue::String(s) => s.clone(),
+        }
+    }
 }
 
 pub struct Config {
@@ -61,6 +71,76 @@ impl Config {
         self.set_var("renderer_raytracer_samples", ConfigValue::I32(1));
         self.set_var("renderer_raytracer_do_lighting", ConfigValue::Bool(false));
         self.set_var("renderer_raytracer_max_steps", ConfigValue::I32(200));
+        self.set_var("renderer_raytracer_max_bounces", ConfigValue::I32(4));
+        // How many times a path scatters off of surfaces before it ends, zero leaves only emission and light sources
+        self.set_var("renderer_raytracer_roulette_depth", ConfigValue::I32(2));
+        // Past this many bounces paths end at random, more likely the less light they still carry
+        self.set_var("renderer_raytracer_octree_depth", ConfigValue::I32(4));
+        // How many levels of the mip chain the octree traversal steps through, capped by the levels the chunk pool has
+        self.set_var(
+            "renderer_raytracer_acceleration",
+            ConfigValue::String("octree".to_string()),
+        );
+        // How rays skip empty space, "octree" steps through the mip chain and "distance_field" jumps by the distance
+        // to the closest filled brick
+        self.set_var(
+            "renderer_raytracer_gi_mode",
+            ConfigValue::String("path_tracing".to_string()),
+        );
+        // How indirect light is gathered, one of the GI_MODES in raytracer/mod.rs. "cone_tracing" traces a few cones
+        // through the mip chain, which is noise free but coarse and skips the denoiser
+        self.set_var(
+            "renderer_debug_view",
+            ConfigValue::String("final".to_string()),
+        );
+        // What the raytracer draws, one of the DEBUG_VIEWS in raytracer/mod.rs. Every view other than "final" is shown
+        // without denoising
+        self.set_var("renderer_sun_direction_x", ConfigValue::F32(0.5));
+        self.set_var("renderer_sun_direction_y", ConfigValue::F32(0.5));
+        self.set_var("renderer_sun_direction_z", ConfigValue::F32(1.0));
+        // Points towards the sun, z is up
+        self.set_var("renderer_sun_color_r", ConfigValue::F32(1.0));
+        self.set_var("renderer_sun_color_g", ConfigValue::F32(1.0));
+        self.set_var("renderer_sun_color_b", ConfigValue::F32(1.0));
+        self.set_var("renderer_sun_sharpness", ConfigValue::F32(2.0));
+        // The exponent of the sun lobe, higher values make the sun smaller
+        self.set_var("renderer_sun_power", ConfigValue::F32(4.0));
+        self.set_var("renderer_sun_time_of_day", ConfigValue::Bool(false));
+        // Moves the sun with game_world_time_of_day, the configured direction is where it stands at noon
+        self.set_var("renderer_sky_color_r", ConfigValue::F32(0.1));
+        self.set_var("renderer_sky_color_g", ConfigValue::F32(0.1));
+        self.set_var("renderer_sky_color_b", ConfigValue::F32(0.1));
+        self.set_var("renderer_sky_power", ConfigValue::F32(2.0));
+        self.set_var(
+            "renderer_sky_model",
+            ConfigValue::String("color".to_string()),
+        );
+        // What escaped rays see, one of the SKY_MODELS in raytracer/mod.rs. "color" is the flat sky color,
+        // "environment_map" samples renderer_environment_map_path and "preetham" evaluates an analytic daylight sky
+        self.set_var("renderer_sky_turbidity", ConfigValue::F32(3.0));
+        // The haziness of the preetham sky, from 2 for a clear sky to 10 for a hazy one
+        self.set_var("renderer_sky_exposure", ConfigValue::F32(0.02));
+        // Scales the luminance of the preetham sky, which is in kcd/m²
+        self.set_var(
+            "renderer_environment_map_path",
+            ConfigValue::String("".to_string()),
+        );
+        // An equirectangular Radiance .hdr image, read once at startup. The sky color is used in its place when it
+        // fails to load
+        self.set_var("renderer_environment_map_intensity", ConfigValue::F32(1.0));
+        self.set_var("renderer_environment_map_rotation", ConfigValue::F32(0.0));
+        // In degrees around the up axis
+        self.set_var("renderer_fog_density", ConfigValue::F32(0.0));
+        // The extinction per world unit at renderer_fog_height, zero turns the fog off
+        self.set_var("renderer_fog_height", ConfigValue::F32(0.0));
+        self.set_var("renderer_fog_height_falloff", ConfigValue::F32(0.0));
+        // How quickly the fog thins out above renderer_fog_height per world unit, zero makes it homogeneous
+        self.set_var("renderer_fog_albedo_r", ConfigValue::F32(0.9));
+        self.set_var("renderer_fog_albedo_g", ConfigValue::F32(0.9));
+        self.set_var("renderer_fog_albedo_b", ConfigValue::F32(0.9));
+        // The share of the light the fog scatters instead of absorbing
+        self.set_var("renderer_fog_anisotropy", ConfigValue::F32(0.3));
+        // The henyey-greenstein g of the fog and of smoke voxels, positive values scatter light forwards
         self.set_var(
             "renderer_denoiser_enable_filtering",
             ConfigValue::Bool(true),
@@ -78,8 +158,65 @@ impl Config {
         self.set_var("game_input_movement_speed", ConfigValue::F32(50.0));
         self.set_var(
             "game_world_file_path",
-            ConfigValue::String("garfield.vox".to_string()),
+            ConfigValue::String("assets/scenes/garfield.evox".to_string()),
+        );
+        self.set_var(
+            "game_world_generator",
+            ConfigValue::String("file".to_string()),
+        );
+        // Either "file" to load game_world_file_path or "perlin" to generate terrain
+        self.set_var("game_world_generator_seed", ConfigValue::I32(0));
+        self.set_var("game_world_generator_octaves", ConfigValue::I32(5));
+        self.set_var("game_world_generator_frequency", ConfigValue::F32(0.01));
+        self.set_var("game_world_generator_sea_level", ConfigValue::I32(16));
+        self.set_var("game_world_width", ConfigValue::I32(0));
+        self.set_var("game_world_height", ConfigValue::I32(0));
+        self.set_var("game_world_length", ConfigValue::I32(0));
+        // The world dimensions in voxels, zero takes the dimension from the loaded scene
+        self.set_var("game_world_voxel_size", ConfigValue::F32(1.0));
+        // The edge length of a voxel in world units
+        self.set_var("game_world_origin_x", ConfigValue::F32(0.0));
+        self.set_var("game_world_origin_y", ConfigValue::F32(0.0));
+        self.set_var("game_world_origin_z", ConfigValue::F32(0.0));
+        // The world space position of the corner of voxel zero
+        self.set_var("game_model_voxel_resolution", ConfigValue::F32(0.0));
+        // Voxels spanned by a unit of a model's mesh, zero keeps meshes in world units
+        self.set_var("game_world_time_of_day", ConfigValue::F32(12.0));
+        // In hours, from 0 to 24
+        self.set_var("game_world_day_length", ConfigValue::F32(120.0));
+        // The seconds a whole day takes, zero stops the clock
+        self.set_var("game_light_kind", ConfigValue::String("none".to_string()));
+        // A light placed in the world at startup, "none", "point", "spot" or "area"
+        self.set_var("game_light_position_x", ConfigValue::F32(0.0));
+        self.set_var("game_light_position_y", ConfigValue::F32(0.0));
+        self.set_var("game_light_position_z", ConfigValue::F32(64.0));
+        self.set_var("game_light_direction_x", ConfigValue::F32(0.0));
+        self.set_var("game_light_direction_y", ConfigValue::F32(0.0));
+        self.set_var("game_light_direction_z", ConfigValue::F32(-1.0));
+        // Where spot and area lights shine, z is up
+        self.set_var("game_light_color_r", ConfigValue::F32(1.0));
+        self.set_var("game_light_color_g", ConfigValue::F32(1.0));
+        self.set_var("game_light_color_b", ConfigValue::F32(1.0));
+        self.set_var("game_light_intensity", ConfigValue::F32(1000.0));
+        // Radiant intensity for point and spot lights, radiance for area lights
+        self.set_var("game_light_angle", ConfigValue::F32(30.0));
+        // The half angle of a spot light cone in degrees
+        self.set_var("game_light_width", ConfigValue::F32(4.0));
+        self.set_var("game_light_length", ConfigValue::F32(4.0));
+        // The size of an area light in world units
+        self.set_var("renderer_world_stream_radius", ConfigValue::I32(8));
+        // Chunks closer to the player than this on every axis are kept on the gpu
+        self.set_var("renderer_world_chunk_pool_size", ConfigValue::I32(5));
+        // Chunk slots per axis of the gpu chunk pool, a chunk takes 512kb
+        self.set_var(
+            "renderer_world_export_path",
+            ConfigValue::String("assets/scenes/export.vox".to_string()),
+        ); // Written when F12 is pressed, the extension picks the format
+        self.set_var(
+            "renderer_mipmap_reduction",
+            ConfigValue::String("average".to_string()),
         );
+        // How a mip texel combines the eight below it, "average", "majority" or "occupancy"
         self.set_var("game_enable_editor", ConfigValue::Bool(false));
         // Editor mode opens up additional controls to easily control do_lighting
         // movement speed, render entities, and edit the world + voxels.
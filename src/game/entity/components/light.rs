@@ -0,0 +1,94 @@
+use glam::{Vec2, Vec3};
+
+use crate::config::Config;
+
+#[derive(Clone, Copy, Debug)]
+pub enum LightKind {
+    Point,
+    Spot { angle: f32 }, // The half angle of the cone in radians
+    Area { size: Vec2 }, // The edge lengths of a rectangle facing along the direction, in world units
+}
+
+// A light source the raytracer samples with shadow rays. Positions and directions are in world space, z is up.
+#[derive(Clone, Copy, Debug)]
+pub struct Light {
+    pub kind: LightKind,
+    pub position: Vec3,
+    pub direction: Vec3, // Where spot and area lights shine, point lights ignore it
+    pub color: Vec3,
+    pub intensity: f32, // Radiant intensity for point and spot lights, radiance for area lights
+}
+
+impl Light {
+    pub fn point(position: Vec3, color: Vec3, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Point,
+            position,
+            direction: -Vec3::Z,
+            color,
+            intensity,
+        }
+    }
+
+    pub fn spot(position: Vec3, direction: Vec3, angle: f32, color: Vec3, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Spot { angle },
+            position,
+            direction: direction.normalize(),
+            color,
+            intensity,
+        }
+    }
+
+    pub fn area(position: Vec3, direction: Vec3, size: Vec2, color: Vec3, intensity: f32) -> Self {
+        Self {
+            kind: LightKind::Area { size },
+            position,
+            direction: direction.normalize(),
+            color,
+            intensity,
+        }
+    }
+
+    // The light described by the game_light config variables, none when game_light_kind is "none"
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let var = |name: &str| config.get_var(name).unwrap().as_f32();
+        let vector = |name: &str, [x, y, z]: [&str; 3]| {
+            Vec3::new(
+                var(&format!("{}_{}", name, x)),
+                var(&format!("{}_{}", name, y)),
+                var(&format!("{}_{}", name, z)),
+            )
+        };
+
+        let position = vector("game_light_position", ["x", "y", "z"]);
+        let direction = vector("game_light_direction", ["x", "y", "z"]);
+        let color = vector("game_light_color", ["r", "g", "b"]);
+        let intensity = var("game_light_intensity");
+
+        let kind = config.get_var("game_light_kind").unwrap().as_string();
+
+        match kind.as_str() {
+            "none" => None,
+            "point" => Some(Self::point(position, color, intensity)),
+            "spot" => Some(Self::spot(
+                position,
+                direction,
+                var("game_light_angle").to_radians(),
+                color,
+                intensity,
+            )),
+            "area" => Some(Self::area(
+                position,
+                direction,
+                Vec2::new(var("game_light_width"), var("game_light_length")),
+                color,
+                intensity,
+            )),
+            _ => {
+                eprintln!("unknown light kind {}, leaving the light out", kind);
+                None
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+pub mod light;
 pub mod model;
 
+pub use light::{Light, LightKind};
 pub use model::Model;
@@ -1,5 +1,6 @@
 mod texture;
 
+use glam::{IVec3, Mat4, Vec3};
 use std::ops::Range;
 use std::path::Path;
 use tobj::LoadOptions;
@@ -19,6 +20,7 @@ pub struct ModelVertex {
     position: [f32; 3],
     tex_coords: [f32; 2],
     normal: [f32; 3],
+    color: [f32; 3],
 }
 
 impl Vertex for ModelVertex {
@@ -43,6 +45,11 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
@@ -66,6 +73,10 @@ pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
     pub transform: Transform,
+    pub voxel_resolution: Option<f32>, // Voxels spanned by a unit of the mesh, none keeps the mesh in world units
+    bounds: (Vec3, Vec3),
+    voxelized_matrix: Option<Mat4>,
+    voxelized_region: Option<(IVec3, IVec3)>,
 }
 
 impl Model {
@@ -80,7 +91,15 @@ impl Model {
         )
         .unwrap();
 
-        let obj_materials = obj_materials.unwrap();
+        // Models without a material library are drawn with a single white material
+        let mut obj_materials = obj_materials.unwrap_or_default();
+
+        if obj_materials.is_empty() {
+            obj_materials.push(tobj::Material {
+                diffuse: [1.0; 3],
+                ..Default::default()
+            });
+        }
 
         // We're assuming that the texture files are stored with the obj file
         let containing_folder = path.as_ref().parent().unwrap();
@@ -114,11 +133,21 @@ impl Model {
         for mat in obj_materials {
             let diffuse_path = mat.diffuse_texture;
 
-            let diffuse_texture = texture::Texture::load(
-                &context.device,
-                &context.queue,
-                containing_folder.join(diffuse_path.clone()),
-            );
+            // Materials without a diffuse texture use their diffuse color instead
+            let diffuse_texture = if diffuse_path.is_empty() {
+                texture::Texture::from_color(
+                    &context.device,
+                    &context.queue,
+                    mat.diffuse,
+                    &mat.name,
+                )
+            } else {
+                texture::Texture::load(
+                    &context.device,
+                    &context.queue,
+                    containing_folder.join(diffuse_path.clone()),
+                )
+            };
 
             let bind_group = context
                 .device
@@ -145,21 +174,36 @@ impl Model {
         }
 
         let mut meshes = Vec::new();
+        let mut bounds = (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN));
         for m in obj_models {
             let mut vertices = Vec::new();
             for i in 0..m.mesh.positions.len() / 3 {
+                // Texture coordinates, normals and vertex colors are optional in obj files
+                let attribute = |values: &Vec<f32>, count: usize, default: f32| {
+                    let mut attribute = [default; 3];
+
+                    if !values.is_empty() {
+                        attribute[..count].copy_from_slice(&values[i * count..(i + 1) * count]);
+                    }
+
+                    attribute
+                };
+
+                let tex_coords = attribute(&m.mesh.texcoords, 2, 0.0);
+
+                let position = [
+                    m.mesh.positions[i * 3],
+                    m.mesh.positions[i * 3 + 1],
+                    m.mesh.positions[i * 3 + 2],
+                ];
+
+                bounds = (bounds.0.min(position.into()), bounds.1.max(position.into()));
+
                 vertices.push(ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
+                    position,
+                    tex_coords: [tex_coords[0], tex_coords[1]],
+                    normal: attribute(&m.mesh.normals, 3, 0.0),
+                    color: attribute(&m.mesh.vertex_color, 3, 1.0),
                 });
             }
 
@@ -190,24 +234,75 @@ impl Model {
             });
         }
 
+        if bounds.0.cmpgt(bounds.1).any() {
+            bounds = (Vec3::ZERO, Vec3::ZERO);
+        }
+
         Self {
             meshes,
             materials,
             transform,
+            voxel_resolution: None,
+            bounds,
+            voxelized_matrix: None,
+            voxelized_region: None,
         }
     }
 
+    // Places the mesh in the world like the transform does, scaled to the voxel resolution if the model has one
+    pub fn model_matrix(&self, voxel_size: f32) -> Mat4 {
+        let scale = self
+            .voxel_resolution
+            .map_or(1.0, |resolution| resolution * voxel_size);
+
+        self.transform.model_matrix() * Mat4::from_scale(Vec3::splat(scale))
+    }
+
+    // The bounding box of the vertices in model space
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        self.bounds
+    }
+
+    // Whether the model matrix changed since the model was last voxelized, or it was never voxelized at all
+    pub fn needs_voxelization(&self, model_matrix: Mat4) -> bool {
+        self.voxelized_matrix != Some(model_matrix)
+    }
+
+    // The voxels the model was last voxelized into, the maximum is exclusive
+    pub fn voxelized_region(&self) -> Option<(IVec3, IVec3)> {
+        self.voxelized_region
+    }
+
+    // Records the model matrix and region the model was voxelized with, returns the region it occupied before
+    pub fn set_voxelized(
+        &mut self,
+        model_matrix: Mat4,
+        region: (IVec3, IVec3),
+    ) -> Option<(IVec3, IVec3)> {
+        self.voxelized_matrix = Some(model_matrix);
+        self.voxelized_region.replace(region)
+    }
+
     pub fn render<'a>(
         &'a self,
         render_pass: &mut wgpu::RenderPass<'a>,
         camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        self.render_instanced(render_pass, camera_bind_group, 0..1);
+    }
+
+    pub fn render_instanced<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        instances: Range<u32>,
     ) {
         for mesh in &self.meshes {
             render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
             render_pass.set_bind_group(1, &self.materials[mesh.material].bind_group, &[]);
             render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
             render_pass.set_bind_group(0, camera_bind_group, &[]);
-            render_pass.draw_indexed(0..mesh.num_elements, 0, 0..1);
+            render_pass.draw_indexed(0..mesh.num_elements, 0, instances.clone());
         }
     }
 }
@@ -71,6 +71,33 @@ impl Texture {
         Self::from_image(device, queue, &img, Some(label))
     }
 
+    // A single texel texture of a linear color
+    pub fn from_color(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        color: [f32; 3],
+        label: &str,
+    ) -> Self {
+        // The texture is srgb so the color gets encoded to come out unchanged when sampled
+        let encode = |channel: f32| {
+            let channel = channel.clamp(0.0, 1.0);
+            let srgb = if channel <= 0.0031308 {
+                channel * 12.92
+            } else {
+                1.055 * channel.powf(1.0 / 2.4) - 0.055
+            };
+
+            (srgb * 255.0).round() as u8
+        };
+
+        let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            1,
+            1,
+            image::Rgba([encode(color[0]), encode(color[1]), encode(color[2]), 255]),
+        ));
+        Self::from_image(device, queue, &img, Some(label))
+    }
+
     pub fn from_image(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
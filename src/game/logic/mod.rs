@@ -11,11 +11,12 @@ use winit::event::KeyboardInput;
 use winit::event::MouseButton;
 use winit::event::VirtualKeyCode::*;
 
+use crate::config::ConfigValue;
 use crate::renderer::RenderContext;
 
 use self::keyboard_tracker::KeyboardTracker;
 
-use super::entity::components::Model;
+use super::entity::components::{Light, Model};
 use super::entity::Handle;
 use super::{Camera, Player, Transform, World};
 
@@ -39,13 +40,16 @@ impl GameLogic {
         let config = world_lock.config.as_ref().unwrap();
         let fov = config.get_var("renderer_fov").unwrap().as_f32();
 
+        // Start above the middle of the world so it's in view, or at the origin when there's nothing to look at
+        let grid = world_lock.voxel_grid.as_ref().unwrap();
+        let spawn = grid.bounds().map_or(Vec3::ZERO, |(min, max)| {
+            let center = (min + max).as_vec3() * 0.5;
+            grid.origin + Vec3::new(center.x, center.y, max.z as f32) * grid.voxel_size
+        });
+
         world_lock.player = Some(Player {
             // TODO Player should be an entity
-            transform: Transform::new(
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::new(0.0, 0.0, 0.0),
-                Vec3::ONE,
-            ),
+            transform: Transform::new(spawn, Vec3::new(0.0, 0.0, 0.0), Vec3::ONE),
             camera: Camera {
                 fov,
                 size: PhysicalSize {
@@ -62,18 +66,25 @@ impl GameLogic {
 
         let handle = world_lock.create_entity(); //TODO instead have a model where you create the entity struct, then add it to the world, instead of adding entity struct to world, then adding components
 
-        world_lock.add_component(
-            handle.clone(),
-            Box::new(Model::load(
-                &context,
-                path,
-                Transform::new(
-                    Vec3::new(0.0, 0.0, 0.0),
-                    Vec3::new(0.0, 0.0, 0.0),
-                    Vec3::new(1.0, 1.0, 1.0),
-                ),
-            )),
+        let mut model = Model::load(
+            &context,
+            path,
+            Transform::new(
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.0),
+                Vec3::new(1.0, 1.0, 1.0),
+            ),
         );
+        let voxel_resolution = world_lock
+            .config
+            .as_ref()
+            .unwrap()
+            .get_var("game_model_voxel_resolution")
+            .unwrap()
+            .as_f32();
+        model.voxel_resolution = Some(voxel_resolution).filter(|resolution| *resolution > 0.0);
+
+        world_lock.add_component(handle.clone(), Box::new(model));
 
         world_lock.add_component(
             handle,
@@ -84,6 +95,11 @@ impl GameLogic {
             )),
         );
 
+        if let Some(light) = Light::from_config(world_lock.config.as_ref().unwrap()) {
+            let handle = world_lock.create_entity();
+            world_lock.add_component(handle, Box::new(light));
+        }
+
         drop(world_lock);
 
         let keyboard_state = KeyboardTracker::new();
@@ -192,5 +208,21 @@ impl GameLogic {
             .unwrap()
             .transform
             .add_rotation(Vec3::new(0.0, 0.0, 0.01));
+
+        // Advance the clock that drives the sun, it stands still while the sun is placed by hand
+        let config = world.config.as_mut().unwrap();
+        let day_length = config.get_var("game_world_day_length").unwrap().as_f32();
+        let time_of_day = config
+            .get_var("renderer_sun_time_of_day")
+            .unwrap()
+            .as_bool();
+
+        if time_of_day && day_length > 0.0 {
+            let time = config.get_var("game_world_time_of_day").unwrap().as_f32();
+            config.set_var(
+                "game_world_time_of_day",
+                ConfigValue::F32((time + delta / day_length * 24.0).rem_euclid(24.0)),
+            );
+        }
     }
 }
@@ -1,3 +1,5 @@
+use std::f32::consts::FRAC_PI_2;
+
 use glam::{Mat4, Vec3, Vec3Swizzles, Vec4, Vec4Swizzles};
 
 #[derive(Debug)]
@@ -28,6 +30,11 @@ impl Transform {
             * Mat4::from_scale(self.scale)
     }
 
+    // Model and view matrices are y up, this maps them back into the z up space of the world
+    pub fn y_up_to_z_up() -> Mat4 {
+        Mat4::from_rotation_x(FRAC_PI_2)
+    }
+
     pub fn position(&self) -> Vec3 {
         self.position
     }
@@ -0,0 +1,109 @@
+use glam::{IVec3, UVec3};
+use perlin_noise::PerlinNoise;
+
+use crate::config::Config;
+
+use super::VoxelGrid;
+
+const WATER_COLOR: [u8; 4] = [38, 84, 160, 255];
+
+// Terrain colors by height above the sea level, as a fraction of the space between the sea level and the top of the
+// world. The last band reaches up to the top.
+const COLOR_BANDS: [(f32, [u8; 4]); 5] = [
+    (0.04, [214, 196, 140, 255]), // Sand
+    (0.45, [86, 152, 62, 255]),   // Grass
+    (0.65, [72, 110, 48, 255]),   // Forest
+    (0.85, [118, 112, 104, 255]), // Rock
+    (1.0, [236, 240, 244, 255]),  // Snow
+];
+
+// Fills a voxel grid with terrain from layered perlin noise, the height of every column is the sum of `octaves`
+// noise layers that double in frequency and halve in amplitude
+pub struct TerrainGenerator {
+    noise: PerlinNoise,
+    seed: i32,
+    octaves: u32,
+    frequency: f64,
+    sea_level: u32,
+}
+
+impl TerrainGenerator {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            noise: PerlinNoise::new(),
+            seed: config
+                .get_var("game_world_generator_seed")
+                .unwrap()
+                .as_i32(),
+            octaves: config
+                .get_var("game_world_generator_octaves")
+                .unwrap()
+                .as_i32()
+                .max(1) as u32,
+            frequency: config
+                .get_var("game_world_generator_frequency")
+                .unwrap()
+                .as_f32() as f64,
+            sea_level: config
+                .get_var("game_world_generator_sea_level")
+                .unwrap()
+                .as_i32()
+                .max(0) as u32,
+        }
+    }
+
+    // Generates the terrain inside of the box from the origin to `size`
+    pub fn generate(&self, grid: &mut VoxelGrid, size: UVec3) {
+        let sea_level = self.sea_level.min(size.z);
+
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let height = (self.height(x, y) * size.z as f64) as u32;
+
+                for z in 0..height.max(sea_level).min(size.z) {
+                    let color = if z < height {
+                        Self::band_color(z, sea_level, size)
+                    } else {
+                        WATER_COLOR
+                    };
+
+                    grid.set(IVec3::new(x as i32, y as i32, z as i32), color, 0);
+                }
+            }
+        }
+    }
+
+    // The terrain height of a column between 0 and 1
+    fn height(&self, x: u32, y: u32) -> f64 {
+        // The seed moves the sampled area of the noise
+        let offset = [self.seed as f64 * 71.37, self.seed as f64 * 53.91];
+
+        let mut frequency = self.frequency;
+        let mut amplitude = 1.0;
+        let mut height = 0.0;
+        let mut total_amplitude = 0.0;
+
+        for _ in 0..self.octaves {
+            height += self.noise.get2d([
+                x as f64 * frequency + offset[0],
+                y as f64 * frequency + offset[1],
+            ]) * amplitude;
+            total_amplitude += amplitude;
+
+            frequency *= 2.0;
+            amplitude *= 0.5;
+        }
+
+        (height / total_amplitude).clamp(0.0, 1.0)
+    }
+
+    fn band_color(z: u32, sea_level: u32, size: UVec3) -> [u8; 4] {
+        let above_sea = z.saturating_sub(sea_level) as f32 / (size.z - sea_level).max(1) as f32;
+
+        COLOR_BANDS
+            .iter()
+            .find(|(top, _)| above_sea < *top)
+            .unwrap_or(&COLOR_BANDS[COLOR_BANDS.len() - 1])
+            .1
+    }
+}
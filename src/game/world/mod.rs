@@ -7,6 +7,7 @@ use std::{
 };
 
 use futures::lock::Mutex;
+use glam::{const_uvec3, UVec3, Vec3};
 
 use crate::{
     config::Config,
@@ -17,6 +18,18 @@ use crate::{
 
 use super::{entity::Handle, Entity};
 
+pub use scene::{Material, Scene, Voxel};
+pub use voxel_grid::{Chunk, VoxelGrid, CHUNK_SIZE};
+
+use generator::TerrainGenerator;
+
+mod generator;
+mod scene;
+mod voxel_grid;
+
+// Used by generators when no dimensions are configured
+const DEFAULT_GENERATED_WORLD_SIZE: UVec3 = const_uvec3!([256, 256, 64]);
+
 #[derive(Default)]
 pub struct World {
     pub entity_count: u64,
@@ -25,6 +38,7 @@ pub struct World {
     pub player: Option<Player>, // These need to go bye bye
     pub ui: Option<Ui>,
     pub config: Option<Config>,
+    pub voxel_grid: Option<VoxelGrid>,
 }
 
 impl World {
@@ -33,9 +47,80 @@ impl World {
         world.config = Some(Config::new());
         world.ui = Some(Ui::new(context));
 
+        let config = world.config.as_ref().unwrap();
+        let configured_size = UVec3::new(
+            config.get_var("game_world_width").unwrap().as_i32().max(0) as u32,
+            config.get_var("game_world_height").unwrap().as_i32().max(0) as u32,
+            config.get_var("game_world_length").unwrap().as_i32().max(0) as u32,
+        );
+
+        let generator = config.get_var("game_world_generator").unwrap().as_string();
+
+        world.voxel_grid = Some(match generator.as_str() {
+            "file" => Self::load_voxel_grid(config, configured_size),
+            "perlin" => {
+                let size = UVec3::select(
+                    configured_size.cmpeq(UVec3::ZERO),
+                    DEFAULT_GENERATED_WORLD_SIZE,
+                    configured_size,
+                );
+
+                let mut grid = VoxelGrid::new();
+                TerrainGenerator::new(config).generate(&mut grid, size);
+                grid
+            }
+            _ => {
+                eprintln!("unknown world generator {}, loading from file", generator);
+                Self::load_voxel_grid(config, configured_size)
+            }
+        });
+
+        let grid = world.voxel_grid.as_mut().unwrap();
+        grid.voxel_size = config.get_var("game_world_voxel_size").unwrap().as_f32();
+        grid.origin = Vec3::new(
+            config.get_var("game_world_origin_x").unwrap().as_f32(),
+            config.get_var("game_world_origin_y").unwrap().as_f32(),
+            config.get_var("game_world_origin_z").unwrap().as_f32(),
+        );
+
         world
     }
 
+    fn load_voxel_grid(config: &Config, configured_size: UVec3) -> VoxelGrid {
+        let path = config.get_var("game_world_file_path").unwrap().as_string();
+
+        let mut scene = match Scene::load(&path) {
+            Ok(scene) => scene,
+            Err(why) => {
+                eprintln!("unable to load scene {}: {}", path, why);
+                return VoxelGrid::new();
+            }
+        };
+
+        // Configured dimensions override the ones of the scene, voxels outside of them are dropped
+        scene.size = UVec3::select(
+            configured_size.cmpeq(UVec3::ZERO),
+            scene.size,
+            configured_size,
+        )
+        .max(UVec3::ONE);
+
+        let dropped = scene
+            .voxels
+            .iter()
+            .filter(|voxel| voxel.position.cmpge(scene.size).any())
+            .count();
+
+        if dropped > 0 {
+            eprintln!(
+                "{} voxels of {} lie outside of the {} world and were dropped",
+                dropped, path, scene.size
+            );
+        }
+
+        VoxelGrid::from_scene(&scene)
+    }
+
     pub fn create_entity(&mut self) -> Handle {
         self.entity_count += 1;
         self.entities
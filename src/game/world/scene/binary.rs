@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+
+use super::SceneError;
+
+// Little endian readers and writers shared by the binary scene formats
+
+pub struct Reader<'a> {
+    pub bytes: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset >= self.bytes.len()
+    }
+
+    pub fn error<S: Into<String>>(&self, message: S) -> SceneError {
+        SceneError::Malformed {
+            offset: self.offset,
+            message: message.into(),
+        }
+    }
+
+    pub fn take(&mut self, count: usize) -> Result<&'a [u8], SceneError> {
+        if self.bytes.len() - self.offset < count {
+            return Err(self.error("unexpected end of file"));
+        }
+
+        let bytes = &self.bytes[self.offset..self.offset + count];
+        self.offset += count;
+
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SceneError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, SceneError> {
+        let bytes = self.take(4)?;
+
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SceneError> {
+        let bytes = self.take(4)?;
+
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, SceneError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    // Unsigned LEB128
+    pub fn read_varint(&mut self) -> Result<u32, SceneError> {
+        let mut value = 0u32;
+
+        for shift in (0..35).step_by(7) {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as u32) << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(self.error("varint is too long"))
+    }
+
+    pub fn read_count(&mut self) -> Result<usize, SceneError> {
+        let count = self.read_i32()?;
+
+        if count < 0 {
+            return Err(self.error(format!("invalid count {}", count)));
+        }
+
+        Ok(count as usize)
+    }
+
+    // Reads the length of a list whose items take at least `item_size` bytes each, so a corrupt count is an error rather
+    // than a huge allocation
+    pub fn read_list_count(&mut self, item_size: usize) -> Result<usize, SceneError> {
+        let count = self.read_count()?;
+
+        if count > (self.bytes.len() - self.offset) / item_size.max(1) {
+            return Err(self.error(format!("count {} exceeds the remaining data", count)));
+        }
+
+        Ok(count)
+    }
+
+    pub fn read_id(&mut self) -> Result<[u8; 4], SceneError> {
+        let bytes = self.take(4)?;
+
+        Ok([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    pub fn read_string(&mut self) -> Result<String, SceneError> {
+        let length = self.read_count()?;
+
+        Ok(String::from_utf8_lossy(self.take(length)?).into_owned())
+    }
+
+    pub fn read_dict(&mut self) -> Result<HashMap<String, String>, SceneError> {
+        let count = self.read_count()?;
+        let mut dict = HashMap::new();
+
+        for _ in 0..count {
+            let key = self.read_string()?;
+            let value = self.read_string()?;
+            dict.insert(key, value);
+        }
+
+        Ok(dict)
+    }
+}
+
+pub struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self { bytes: Vec::new() }
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn write_varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.bytes.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+
+        self.bytes.push(value as u8);
+    }
+
+    pub fn write_string(&mut self, value: &str) {
+        self.write_i32(value.len() as i32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn write_dict(&mut self, dict: &[(&str, String)]) {
+        self.write_i32(dict.len() as i32);
+
+        for (key, value) in dict {
+            self.write_string(key);
+            self.write_string(value);
+        }
+    }
+
+    pub fn write_chunk(&mut self, id: &[u8; 4], content: Writer) {
+        self.bytes.extend_from_slice(id);
+        self.write_i32(content.bytes.len() as i32);
+        self.write_i32(0);
+        self.bytes.extend_from_slice(&content.bytes);
+    }
+}
@@ -0,0 +1,421 @@
+use std::str::FromStr;
+
+use glam::UVec3;
+
+use super::{
+    binary::{Reader, Writer},
+    palette::build_palette,
+    Material, Scene, SceneError, Voxel,
+};
+
+// Binary evox files are laid out as
+//   magic `EVOX`, version u32, flags u32, width u32, height u32, depth u32
+//   palette: count u32 followed by that many rgba colors, palette index 0 is empty space
+//   materials (FLAG_MATERIALS only): count u32 followed by that many materials of five f32, six with FLAG_DENSITY
+//   voxels: runs of (varint length, varint palette index) covering every cell in x, y, z order
+//   material ids (FLAG_MATERIALS only): runs of (varint length, u8 material) covering every filled cell
+const MAGIC: &[u8; 4] = b"EVOX";
+const VERSION: u32 = 2;
+const FLAG_MATERIALS: u32 = 1;
+const FLAG_DENSITY: u32 = 2; // Materials carry a density after the emission
+
+// Palette indices are stored as varints, but the dense grid used while writing holds them as u16
+const MAX_PALETTE_COLORS: usize = u16::MAX as usize;
+
+// Guards against allocating absurd amounts of memory for a corrupt header
+const MAX_CELLS: u64 = 1 << 31;
+
+// Files without the magic number are read as legacy text evox
+pub fn read(bytes: &[u8]) -> Result<Scene, SceneError> {
+    if bytes.starts_with(MAGIC) {
+        return read_binary(bytes);
+    }
+
+    let source = std::str::from_utf8(bytes).map_err(|error| SceneError::Malformed {
+        offset: error.valid_up_to(),
+        message: "file is neither binary nor text evox".to_string(),
+    })?;
+
+    parse_text(source)
+}
+
+fn read_binary(bytes: &[u8]) -> Result<Scene, SceneError> {
+    let mut reader = Reader::new(bytes);
+    reader.take(MAGIC.len())?;
+
+    let version = reader.read_u32()?;
+
+    if version != VERSION {
+        return Err(reader.error(format!("unsupported evox version {}", version)));
+    }
+
+    let flags = reader.read_u32()?;
+    let size = UVec3::new(reader.read_u32()?, reader.read_u32()?, reader.read_u32()?);
+    let cells = match cell_count(size) {
+        Some(cells) => cells,
+        None => {
+            return Err(reader.error(format!(
+                "{}x{}x{} scene is too large",
+                size.x, size.y, size.z
+            )))
+        }
+    };
+
+    let palette_count = reader.read_u32()? as usize;
+    let mut palette = Vec::with_capacity(palette_count.min(MAX_PALETTE_COLORS));
+
+    for _ in 0..palette_count {
+        let color = reader.take(4)?;
+        palette.push([color[0], color[1], color[2], color[3]]);
+    }
+
+    let mut materials = vec![Material::default()];
+
+    if flags & FLAG_MATERIALS != 0 {
+        materials.clear();
+
+        for _ in 0..reader.read_u32()? {
+            materials.push(Material {
+                roughness: reader.read_f32()?,
+                metalness: reader.read_f32()?,
+                ior: reader.read_f32()?,
+                transmission: reader.read_f32()?,
+                emission: reader.read_f32()?,
+                density: if flags & FLAG_DENSITY != 0 {
+                    reader.read_f32()?
+                } else {
+                    0.0
+                },
+            });
+        }
+
+        if materials.is_empty() {
+            return Err(reader.error("material table is empty"));
+        }
+    }
+
+    let mut voxels = Vec::new();
+    let mut cell = 0u64;
+
+    while cell < cells {
+        let length = reader.read_varint()? as u64;
+        let index = reader.read_varint()? as usize;
+
+        if length == 0 || length > cells - cell {
+            return Err(reader.error(format!("invalid run length {}", length)));
+        }
+
+        if index > palette.len() {
+            return Err(reader.error(format!("palette index {} out of range", index)));
+        }
+
+        if index != 0 {
+            for filled in cell..cell + length {
+                voxels.push(Voxel {
+                    position: UVec3::new(
+                        (filled % size.x as u64) as u32,
+                        (filled / size.x as u64 % size.y as u64) as u32,
+                        (filled / (size.x as u64 * size.y as u64)) as u32,
+                    ),
+                    color: palette[index - 1],
+                    material: 0,
+                });
+            }
+        }
+
+        cell += length;
+    }
+
+    if flags & FLAG_MATERIALS != 0 {
+        let mut voxel = 0;
+
+        while voxel < voxels.len() {
+            let length = reader.read_varint()? as usize;
+            let material = reader.read_u8()?;
+
+            if length == 0 || length > voxels.len() - voxel {
+                return Err(reader.error(format!("invalid run length {}", length)));
+            }
+
+            if material as usize >= materials.len() {
+                return Err(reader.error(format!("material {} out of range", material)));
+            }
+
+            for target in &mut voxels[voxel..voxel + length] {
+                target.material = material;
+            }
+
+            voxel += length;
+        }
+    }
+
+    Ok(Scene {
+        size,
+        voxels,
+        materials,
+    })
+}
+
+// Legacy text evox files start with a `WxHxD` header followed by one voxel per line, either as
+// `x, y, z` or as `x, y, z, r, g, b[, a]`
+fn parse_text(source: &str) -> Result<Scene, SceneError> {
+    let mut lines = source
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty());
+
+    let (line, header) = lines.next().ok_or(SceneError::Parse {
+        line: 1,
+        message: "missing WxHxD header".to_string(),
+    })?;
+
+    let size = parse_header(line, header)?;
+
+    let mut voxels = Vec::new();
+
+    for (line, row) in lines {
+        let values = row.split(',').map(|value| value.trim()).collect::<Vec<_>>();
+
+        if ![3, 6, 7].contains(&values.len()) {
+            return Err(SceneError::Parse {
+                line,
+                message: format!("expected 3, 6 or 7 columns, found {}", values.len()),
+            });
+        }
+
+        let position = UVec3::new(
+            parse_value(line, values[0])?,
+            parse_value(line, values[1])?,
+            parse_value(line, values[2])?,
+        );
+
+        let color = match values.len() {
+            3 => [255; 4],
+            _ => [
+                parse_value(line, values[3])?,
+                parse_value(line, values[4])?,
+                parse_value(line, values[5])?,
+                match values.get(6) {
+                    Some(alpha) => parse_value(line, alpha)?,
+                    None => 255,
+                },
+            ],
+        };
+
+        if position.cmpge(size).any() {
+            return Err(SceneError::Parse {
+                line,
+                message: format!(
+                    "voxel {} lies outside of the {}x{}x{} scene",
+                    position, size.x, size.y, size.z
+                ),
+            });
+        }
+
+        voxels.push(Voxel {
+            position,
+            color,
+            material: 0,
+        });
+    }
+
+    Ok(Scene {
+        size,
+        voxels,
+        materials: vec![Material::default()],
+    })
+}
+
+fn parse_header(line: usize, header: &str) -> Result<UVec3, SceneError> {
+    let dimensions = header
+        .split('x')
+        .map(|value| value.trim())
+        .collect::<Vec<_>>();
+
+    if dimensions.len() != 3 {
+        return Err(SceneError::Parse {
+            line,
+            message: format!("expected a WxHxD header, found {:?}", header),
+        });
+    }
+
+    Ok(UVec3::new(
+        parse_value(line, dimensions[0])?,
+        parse_value(line, dimensions[1])?,
+        parse_value(line, dimensions[2])?,
+    ))
+}
+
+fn parse_value<T: FromStr>(line: usize, value: &str) -> Result<T, SceneError> {
+    value.parse::<T>().map_err(|_| SceneError::Parse {
+        line,
+        message: format!("invalid value {:?}", value),
+    })
+}
+
+// Fails for scenes the reader would refuse as too large and for voxels outside of the scene
+pub fn write(scene: &Scene) -> Result<Vec<u8>, SceneError> {
+    let size = scene.size;
+    let cells = match cell_count(size) {
+        Some(cells) => cells,
+        None => {
+            return Err(SceneError::Invalid(format!(
+                "{}x{}x{} scene is too large",
+                size.x, size.y, size.z
+            )))
+        }
+    };
+
+    if let Some(voxel) = scene
+        .voxels
+        .iter()
+        .find(|voxel| voxel.position.cmpge(size).any())
+    {
+        return Err(SceneError::Invalid(format!(
+            "voxel at {} lies outside of the {}x{}x{} scene",
+            voxel.position, size.x, size.y, size.z
+        )));
+    }
+
+    let (palette, indices) = build_palette(&scene.voxels, MAX_PALETTE_COLORS);
+
+    // Later voxels overwrite earlier ones at the same position
+    let mut cells = vec![0u16; cells as usize];
+    let mut cell_materials = vec![0u8; cells.len()];
+
+    for voxel in &scene.voxels {
+        let position = voxel.position;
+        let cell = position.x as usize
+            + (position.y as usize + position.z as usize * size.y as usize) * size.x as usize;
+
+        cells[cell] = indices[&voxel.color] as u16;
+        cell_materials[cell] = voxel.material;
+    }
+
+    let has_materials = scene.materials.len() > 1 || cell_materials.iter().any(|x| *x != 0);
+    let has_density = has_materials && scene.materials.iter().any(|x| x.density != 0.0);
+
+    let mut writer = Writer::new();
+    writer.bytes.extend_from_slice(MAGIC);
+    writer.write_u32(VERSION);
+    writer.write_u32(
+        if has_materials { FLAG_MATERIALS } else { 0 } | if has_density { FLAG_DENSITY } else { 0 },
+    );
+    writer.write_u32(size.x);
+    writer.write_u32(size.y);
+    writer.write_u32(size.z);
+
+    writer.write_u32(palette.len() as u32);
+    for color in &palette {
+        writer.bytes.extend_from_slice(color);
+    }
+
+    if has_materials {
+        writer.write_u32(scene.materials.len() as u32);
+
+        for material in &scene.materials {
+            writer.write_f32(material.roughness);
+            writer.write_f32(material.metalness);
+            writer.write_f32(material.ior);
+            writer.write_f32(material.transmission);
+            writer.write_f32(material.emission);
+
+            if has_density {
+                writer.write_f32(material.density);
+            }
+        }
+    }
+
+    for (length, index) in runs(cells.iter().copied()) {
+        writer.write_varint(length);
+        writer.write_varint(index as u32);
+    }
+
+    if has_materials {
+        let filled = cells
+            .iter()
+            .zip(cell_materials.iter())
+            .filter(|(index, _)| **index != 0)
+            .map(|(_, material)| *material);
+
+        for (length, material) in runs(filled) {
+            writer.write_varint(length);
+            writer.bytes.push(material);
+        }
+    }
+
+    Ok(writer.bytes)
+}
+
+// The number of cells in a scene, none when it's larger than MAX_CELLS
+fn cell_count(size: UVec3) -> Option<u64> {
+    (size.x as u64)
+        .checked_mul(size.y as u64)
+        .and_then(|cells| cells.checked_mul(size.z as u64))
+        .filter(|cells| *cells <= MAX_CELLS)
+}
+
+// Run length encodes a sequence into (length, value) pairs
+fn runs<T: PartialEq + Copy, I: Iterator<Item = T>>(values: I) -> Vec<(u32, T)> {
+    let mut runs: Vec<(u32, T)> = Vec::new();
+
+    for value in values {
+        match runs.last_mut() {
+            Some((length, last)) if *last == value => *length += 1,
+            _ => runs.push((1, value)),
+        }
+    }
+
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn voxels(scene: &Scene) -> HashMap<UVec3, ([u8; 4], u8)> {
+        scene
+            .voxels
+            .iter()
+            .map(|voxel| (voxel.position, (voxel.color, voxel.material)))
+            .collect()
+    }
+
+    #[test]
+    fn shipped_scenes_round_trip() {
+        for name in ["garfield", "tram"] {
+            let scene = Scene::load(format!(
+                "{}/assets/scenes/{}.evox",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ))
+            .unwrap();
+
+            let read = read(&write(&scene).unwrap()).unwrap();
+
+            assert_eq!(read.size, scene.size, "{}", name);
+            assert_eq!(voxels(&read), voxels(&scene), "{}", name);
+        }
+    }
+
+    #[test]
+    fn scenes_that_cant_be_stored_are_errors() {
+        let voxel = |position| Voxel {
+            position,
+            color: [255; 4],
+            material: 0,
+        };
+        let scene = |size, voxels| Scene {
+            size,
+            voxels,
+            materials: vec![Material::default()],
+        };
+
+        assert!(write(&scene(UVec3::splat(4), vec![voxel(UVec3::splat(3))])).is_ok());
+        assert!(write(&scene(UVec3::splat(4), vec![voxel(UVec3::new(0, 4, 0))])).is_err());
+        assert!(write(&scene(UVec3::splat(u32::MAX), Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+mod binary;
+mod evox;
+mod palette;
+mod vox;
+
+use std::{error::Error, fmt, fs, io, path::Path};
+
+use glam::UVec3;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Voxel {
+    pub position: UVec3,
+    pub color: [u8; 4],
+    pub material: u8, // Index into the materials of the scene
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Material {
+    pub roughness: f32,
+    pub metalness: f32,
+    pub ior: f32,
+    pub transmission: f32,
+    pub emission: f32,
+    pub density: f32, // Voxels with a density are smoke or clouds instead of surfaces, it is the extinction per voxel
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            roughness: 1.0,
+            metalness: 0.0,
+            ior: 1.5,
+            transmission: 0.0,
+            emission: 0.0,
+            density: 0.0,
+        }
+    }
+}
+
+pub struct Scene {
+    pub size: UVec3,
+    pub voxels: Vec<Voxel>,
+    pub materials: Vec<Material>, // The first material is the default diffuse material
+}
+
+#[derive(Debug)]
+pub enum SceneError {
+    Io(io::Error),
+    Parse { line: usize, message: String },
+    Malformed { offset: usize, message: String },
+    Unsupported(String),
+    Invalid(String), // The scene can't be stored in the requested format
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SceneError::Io(error) => write!(f, "{}", error),
+            SceneError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            SceneError::Malformed { offset, message } => {
+                write!(f, "byte {}: {}", offset, message)
+            }
+            SceneError::Unsupported(extension) => {
+                write!(f, "unsupported scene format {:?}", extension)
+            }
+            SceneError::Invalid(message) => write!(f, "invalid scene: {}", message),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+impl From<io::Error> for SceneError {
+    fn from(error: io::Error) -> Self {
+        SceneError::Io(error)
+    }
+}
+
+impl Scene {
+    // The format is chosen by the file extension
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SceneError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+
+        match extension {
+            "evox" => evox::read(&fs::read(path)?),
+            "vox" => vox::parse(&fs::read(path)?),
+            extension => Err(SceneError::Unsupported(extension.to_string())),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SceneError> {
+        let path = path.as_ref();
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+
+        match extension {
+            "evox" => fs::write(path, evox::write(self)?)?,
+            "vox" => fs::write(path, vox::write(self))?,
+            extension => return Err(SceneError::Unsupported(extension.to_string())),
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::{BinaryHeap, HashMap};
+
+use super::Voxel;
+
+// Returns the palette colors and the palette index of every color in use. Palette indices start at 1 since 0
+// is reserved for empty space. Scenes with more colors than `max_colors` are reduced with median cut quantization.
+pub fn build_palette(
+    voxels: &[Voxel],
+    max_colors: usize,
+) -> (Vec<[u8; 4]>, HashMap<[u8; 4], usize>) {
+    let mut counts = HashMap::new();
+
+    for voxel in voxels {
+        *counts.entry(voxel.color).or_insert(0u64) += 1;
+    }
+
+    let colors = counts.into_iter().collect::<Vec<_>>();
+
+    // Scenes that fit keep every color exactly
+    let boxes = if colors.len() <= max_colors {
+        colors.into_iter().map(|color| vec![color]).collect()
+    } else {
+        median_cut(colors, max_colors)
+    };
+
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+
+    for colors in boxes.iter().filter(|colors| !colors.is_empty()) {
+        let total = colors.iter().map(|(_, count)| count).sum::<u64>();
+        let mut average = [0u64; 4];
+
+        for (color, count) in colors {
+            for channel in 0..4 {
+                average[channel] += color[channel] as u64 * count;
+            }
+        }
+
+        palette.push(average.map(|channel| ((channel + total / 2) / total) as u8));
+
+        for (color, _) in colors {
+            indices.insert(*color, palette.len());
+        }
+    }
+
+    (palette, indices)
+}
+
+// Splits the box with the widest channel until there are `max_colors` boxes or every box holds a single color
+fn median_cut(colors: Vec<([u8; 4], u64)>, max_colors: usize) -> Vec<Vec<([u8; 4], u64)>> {
+    // The widest box is kept on top of a heap, ordered by its range, then its color count
+    let split_key = |colors: &[([u8; 4], u64)], index: usize| {
+        let (channel, range) = widest_channel(colors);
+        (range, colors.len(), channel, index)
+    };
+
+    let mut boxes = vec![colors];
+    let mut widest = BinaryHeap::new();
+
+    if boxes[0].len() > 1 {
+        widest.push(split_key(&boxes[0], 0));
+    }
+
+    while boxes.len() < max_colors {
+        let (index, channel) = match widest.pop() {
+            Some((_, _, channel, index)) => (index, channel),
+            None => break,
+        };
+
+        let mut colors = std::mem::take(&mut boxes[index]);
+        colors.sort_unstable_by_key(|(color, _)| color[channel]);
+
+        // Split at the weighted median, keeping at least one color on each side
+        let total = colors.iter().map(|(_, count)| count).sum::<u64>();
+        let mut accumulated = 0;
+        let mut split = 1;
+
+        for (position, (_, count)) in colors.iter().enumerate() {
+            accumulated += count;
+            if accumulated * 2 >= total {
+                split = (position + 1).min(colors.len() - 1);
+                break;
+            }
+        }
+
+        let upper = colors.split_off(split);
+        boxes[index] = colors;
+        boxes.push(upper);
+
+        for index in [index, boxes.len() - 1] {
+            if boxes[index].len() > 1 {
+                widest.push(split_key(&boxes[index], index));
+            }
+        }
+    }
+
+    boxes
+}
+
+fn widest_channel(colors: &[([u8; 4], u64)]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let min = colors
+                .iter()
+                .map(|(color, _)| color[channel])
+                .min()
+                .unwrap();
+            let max = colors
+                .iter()
+                .map(|(color, _)| color[channel])
+                .max()
+                .unwrap();
+            (channel, max - min)
+        })
+        .max_by_key(|(_, range)| *range)
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::UVec3;
+
+    use super::*;
+
+    fn voxels(count: u32) -> Vec<Voxel> {
+        (0..count)
+            .map(|i| Voxel {
+                position: UVec3::new(i % 200, i / 200, 0),
+                color: [i as u8, (i >> 8) as u8, (i >> 16) as u8, 255],
+                material: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn colors_that_fit_are_kept() {
+        let voxels = voxels(40_000);
+        let (palette, indices) = build_palette(&voxels, u16::MAX as usize);
+
+        assert_eq!(palette.len(), 40_000);
+
+        for voxel in &voxels {
+            assert_eq!(palette[indices[&voxel.color] - 1], voxel.color);
+        }
+    }
+
+    #[test]
+    fn colors_are_reduced_to_the_limit() {
+        let voxels = voxels(40_000);
+        let (palette, indices) = build_palette(&voxels, 255);
+
+        assert_eq!(palette.len(), 255);
+        assert!(voxels.iter().all(|voxel| indices[&voxel.color] <= 255));
+    }
+}
@@ -0,0 +1,746 @@
+use std::collections::HashMap;
+
+use glam::{IVec3, UVec3};
+
+use super::{
+    binary::{Reader, Writer},
+    palette::build_palette,
+    Material, Scene, SceneError, Voxel,
+};
+
+// MagicaVoxel .vox reader, see https://github.com/ephtracy/voxel-model/blob/master/MagicaVoxel-file-format-vox.txt
+// and MagicaVoxel-file-format-vox-extension.txt for the scene graph and material chunks
+
+// Scene graphs deeper than this are assumed to contain a cycle
+const MAX_NODE_DEPTH: u32 = 64;
+
+// Translations further out than this are assumed to be corrupt, it keeps positions far from overflowing even when the
+// translations of every level of the scene graph add up
+const MAX_TRANSLATION: i32 = 1 << 20;
+
+// Shapes can place the same model many times, this stops a small file from expanding into more voxels than fit in
+// memory
+const MAX_PLACED_VOXELS: usize = 1 << 26;
+
+struct Model {
+    size: IVec3,
+    voxels: Vec<[u8; 4]>,
+}
+
+// Rotations in a .vox file are always signed permutation matrices, so they are stored as rows of integers
+#[derive(Clone, Copy)]
+struct Transform {
+    rotation: [IVec3; 3],
+    translation: IVec3,
+}
+
+impl Transform {
+    const IDENTITY: Self = Self {
+        rotation: [IVec3::X, IVec3::Y, IVec3::Z],
+        translation: IVec3::ZERO,
+    };
+
+    fn apply(&self, position: IVec3) -> IVec3 {
+        IVec3::new(
+            self.rotation[0].dot(position),
+            self.rotation[1].dot(position),
+            self.rotation[2].dot(position),
+        ) + self.translation
+    }
+
+    // Returns the transform that applies `child` first and then `self`
+    fn then(&self, child: &Transform) -> Self {
+        let columns = [
+            self.rotate(IVec3::new(
+                child.rotation[0].x,
+                child.rotation[1].x,
+                child.rotation[2].x,
+            )),
+            self.rotate(IVec3::new(
+                child.rotation[0].y,
+                child.rotation[1].y,
+                child.rotation[2].y,
+            )),
+            self.rotate(IVec3::new(
+                child.rotation[0].z,
+                child.rotation[1].z,
+                child.rotation[2].z,
+            )),
+        ];
+
+        Self {
+            rotation: [
+                IVec3::new(columns[0].x, columns[1].x, columns[2].x),
+                IVec3::new(columns[0].y, columns[1].y, columns[2].y),
+                IVec3::new(columns[0].z, columns[1].z, columns[2].z),
+            ],
+            translation: self.apply(child.translation),
+        }
+    }
+
+    fn rotate(&self, direction: IVec3) -> IVec3 {
+        IVec3::new(
+            self.rotation[0].dot(direction),
+            self.rotation[1].dot(direction),
+            self.rotation[2].dot(direction),
+        )
+    }
+}
+
+enum Node {
+    Transform {
+        child: i32,
+        transform: Transform,
+        hidden: bool,
+    },
+    Group {
+        children: Vec<i32>,
+    },
+    Shape {
+        models: Vec<i32>,
+    },
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Scene, SceneError> {
+    let mut reader = Reader::new(bytes);
+
+    if reader.read_id()? != *b"VOX " {
+        return Err(reader.error("missing VOX magic number"));
+    }
+
+    let _version = reader.read_i32()?;
+
+    if reader.read_id()? != *b"MAIN" {
+        return Err(reader.error("missing MAIN chunk"));
+    }
+
+    let _content_size = reader.read_count()?;
+    let children_size = reader.read_count()?;
+    let mut chunks = Reader {
+        bytes: &bytes[..reader.offset + children_size.min(bytes.len() - reader.offset)],
+        offset: reader.offset,
+    };
+
+    let mut models = Vec::new();
+    let mut size = None;
+    let mut palette = default_palette();
+    let mut nodes = HashMap::new();
+    let mut vox_materials = HashMap::new();
+
+    while !chunks.is_empty() {
+        let id = chunks.read_id()?;
+        let content_size = chunks.read_count()?;
+        let children_size = chunks.read_count()?;
+        let start = chunks.offset;
+        chunks.take(content_size + children_size)?;
+        let mut content = Reader {
+            bytes: &bytes[..start + content_size],
+            offset: start,
+        };
+
+        match &id {
+            b"SIZE" => {
+                let model_size = IVec3::new(
+                    content.read_i32()?,
+                    content.read_i32()?,
+                    content.read_i32()?,
+                );
+
+                if model_size.min_element() < 1 || model_size.max_element() > MAX_MODEL_SIZE as i32
+                {
+                    return Err(content.error(format!("invalid model size {}", model_size)));
+                }
+
+                size = Some(model_size);
+            }
+            b"XYZI" => {
+                let size = size
+                    .take()
+                    .ok_or_else(|| content.error("XYZI chunk without a preceding SIZE chunk"))?;
+                let count = content.read_list_count(4)?;
+                let mut voxels = Vec::with_capacity(count);
+
+                for _ in 0..count {
+                    let voxel = content.take(4)?;
+                    voxels.push([voxel[0], voxel[1], voxel[2], voxel[3]]);
+                }
+
+                models.push(Model { size, voxels });
+            }
+            b"RGBA" => {
+                // Palette index 0 is reserved for empty space, so the chunk is shifted by one
+                for index in 1..256 {
+                    let color = content.take(4)?;
+                    palette[index] = [color[0], color[1], color[2], color[3]];
+                }
+            }
+            b"nTRN" => {
+                let id = content.read_i32()?;
+                let attributes = content.read_dict()?;
+                let child = content.read_i32()?;
+                let _reserved = content.read_i32()?;
+                let _layer = content.read_i32()?;
+                let frames = content.read_count()?;
+
+                let mut transform = Transform::IDENTITY;
+
+                // Only the first animation frame is used
+                for frame in 0..frames {
+                    let frame_attributes = content.read_dict()?;
+
+                    if frame != 0 {
+                        continue;
+                    }
+
+                    if let Some(rotation) = frame_attributes.get("_r") {
+                        let bits = rotation.parse::<u8>().map_err(|_| {
+                            content.error(format!("invalid rotation {:?}", rotation))
+                        })?;
+                        transform.rotation = decode_rotation(bits)
+                            .ok_or_else(|| content.error(format!("invalid rotation {}", bits)))?;
+                    }
+
+                    if let Some(translation) = frame_attributes.get("_t") {
+                        let values = translation
+                            .split_whitespace()
+                            .map(|value| value.parse::<i32>())
+                            .collect::<Result<Vec<_>, _>>()
+                            .ok()
+                            .filter(|values| {
+                                values.len() == 3
+                                    && values.iter().all(|value| value.abs() <= MAX_TRANSLATION)
+                            })
+                            .ok_or_else(|| {
+                                content.error(format!("invalid translation {:?}", translation))
+                            })?;
+                        transform.translation = IVec3::new(values[0], values[1], values[2]);
+                    }
+                }
+
+                nodes.insert(
+                    id,
+                    Node::Transform {
+                        child,
+                        transform,
+                        hidden: attributes.get("_hidden").map(|x| x.as_str()) == Some("1"),
+                    },
+                );
+            }
+            b"nGRP" => {
+                let id = content.read_i32()?;
+                let _attributes = content.read_dict()?;
+                let count = content.read_list_count(4)?;
+                let mut children = Vec::with_capacity(count);
+
+                for _ in 0..count {
+                    children.push(content.read_i32()?);
+                }
+
+                nodes.insert(id, Node::Group { children });
+            }
+            b"nSHP" => {
+                let id = content.read_i32()?;
+                let _attributes = content.read_dict()?;
+                let count = content.read_list_count(8)?;
+                let mut shape_models = Vec::with_capacity(count);
+
+                for _ in 0..count {
+                    shape_models.push(content.read_i32()?);
+                    let _model_attributes = content.read_dict()?;
+                }
+
+                nodes.insert(
+                    id,
+                    Node::Shape {
+                        models: shape_models,
+                    },
+                );
+            }
+            b"MATL" => {
+                let id = content.read_i32()?;
+                let properties = content.read_dict()?;
+
+                if let Some(material) = parse_material(&properties) {
+                    vox_materials.insert(id, material);
+                }
+            }
+            _ => (), // PACK, LAYR, rOBJ, rCAM, NOTE, IMAP and unknown chunks are ignored
+        }
+    }
+
+    if models.is_empty() {
+        return Err(chunks.error("file contains no models"));
+    }
+
+    let mut placed = Vec::new();
+    let mut bounds = None;
+
+    if nodes.is_empty() {
+        // Files without a scene graph place every model at the origin without centering
+        for model in &models {
+            extend_bounds(&mut bounds, IVec3::ZERO, model.size - IVec3::ONE);
+
+            for voxel in &model.voxels {
+                placed.push((
+                    IVec3::new(voxel[0] as i32, voxel[1] as i32, voxel[2] as i32),
+                    voxel[3],
+                ));
+            }
+        }
+    } else {
+        place_node(
+            &nodes,
+            &models,
+            0,
+            Transform::IDENTITY,
+            0,
+            &mut placed,
+            &mut bounds,
+        )?;
+    }
+
+    // The scene spans the boxes of the models, voxels outside of their box are kept too
+    for (position, _) in &placed {
+        extend_bounds(&mut bounds, *position, *position);
+    }
+
+    let (min, max) = bounds.ok_or_else(|| chunks.error("file contains no visible models"))?;
+
+    let mut materials = vec![Material::default()];
+    let mut material_slots = HashMap::new();
+    let mut voxels = Vec::with_capacity(placed.len());
+
+    for (position, index) in placed {
+        let material = match vox_materials.get(&(index as i32)) {
+            Some(material) => *material_slots.entry(index).or_insert_with(|| {
+                materials.push(*material);
+                (materials.len() - 1) as u8
+            }),
+            None => 0,
+        };
+
+        voxels.push(Voxel {
+            position: (position - min).as_uvec3(),
+            color: palette[index as usize],
+            material,
+        });
+    }
+
+    Ok(Scene {
+        size: (max - min + IVec3::ONE).as_uvec3(),
+        voxels,
+        materials,
+    })
+}
+
+fn place_node(
+    nodes: &HashMap<i32, Node>,
+    models: &[Model],
+    id: i32,
+    transform: Transform,
+    depth: u32,
+    placed: &mut Vec<(IVec3, u8)>,
+    bounds: &mut Option<(IVec3, IVec3)>,
+) -> Result<(), SceneError> {
+    if depth > MAX_NODE_DEPTH {
+        return Err(SceneError::Malformed {
+            offset: 0,
+            message: "scene graph is too deep or contains a cycle".to_string(),
+        });
+    }
+
+    let node = nodes.get(&id).ok_or_else(|| SceneError::Malformed {
+        offset: 0,
+        message: format!("scene graph references missing node {}", id),
+    })?;
+
+    match node {
+        Node::Transform {
+            child,
+            transform: local,
+            hidden,
+        } => {
+            if !hidden {
+                place_node(
+                    nodes,
+                    models,
+                    *child,
+                    transform.then(local),
+                    depth + 1,
+                    placed,
+                    bounds,
+                )?;
+            }
+        }
+        Node::Group { children } => {
+            for child in children {
+                place_node(nodes, models, *child, transform, depth + 1, placed, bounds)?;
+            }
+        }
+        Node::Shape {
+            models: shape_models,
+        } => {
+            for model_id in shape_models {
+                let model =
+                    models
+                        .get(*model_id as usize)
+                        .ok_or_else(|| SceneError::Malformed {
+                            offset: 0,
+                            message: format!("shape references missing model {}", model_id),
+                        })?;
+
+                if placed.len() + model.voxels.len() > MAX_PLACED_VOXELS {
+                    return Err(SceneError::Malformed {
+                        offset: 0,
+                        message: format!("scene places more than {} voxels", MAX_PLACED_VOXELS),
+                    });
+                }
+
+                // Models are rotated and translated about their center
+                let pivot = model.size / 2;
+                let corners = [
+                    transform.apply(-pivot),
+                    transform.apply(model.size - IVec3::ONE - pivot),
+                ];
+                extend_bounds(
+                    bounds,
+                    corners[0].min(corners[1]),
+                    corners[0].max(corners[1]),
+                );
+
+                for voxel in &model.voxels {
+                    let position = IVec3::new(voxel[0] as i32, voxel[1] as i32, voxel[2] as i32);
+                    placed.push((transform.apply(position - pivot), voxel[3]));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn extend_bounds(bounds: &mut Option<(IVec3, IVec3)>, min: IVec3, max: IVec3) {
+    *bounds = Some(match *bounds {
+        Some((low, high)) => (low.min(min), high.max(max)),
+        None => (min, max),
+    });
+}
+
+// The rotation byte stores the column of the non zero entry of the first two rows and the sign of each row
+fn decode_rotation(bits: u8) -> Option<[IVec3; 3]> {
+    let first = (bits & 3) as usize;
+    let second = ((bits >> 2) & 3) as usize;
+
+    if first > 2 || second > 2 || first == second {
+        return None;
+    }
+
+    let third = 3 - first - second;
+    let mut rotation = [IVec3::ZERO; 3];
+
+    for (row, &(column, sign_bit)) in [(first, 4), (second, 5), (third, 6)].iter().enumerate() {
+        rotation[row][column] = if bits & (1 << sign_bit) != 0 { -1 } else { 1 };
+    }
+
+    Some(rotation)
+}
+
+fn parse_material(properties: &HashMap<String, String>) -> Option<Material> {
+    let get = |name: &str| {
+        properties
+            .get(name)
+            .and_then(|value| value.parse::<f32>().ok())
+    };
+
+    let kind = properties
+        .get("_type")
+        .map(|x| x.as_str())
+        .unwrap_or("_diffuse");
+
+    if kind == "_diffuse" {
+        return None;
+    }
+
+    // Older files only store a single `_weight` that applies to the material type
+    let weight = get("_weight").unwrap_or(1.0);
+    let default = Material::default();
+
+    Some(Material {
+        roughness: get("_rough").unwrap_or(default.roughness),
+        metalness: get("_metal").unwrap_or(if kind == "_metal" { weight } else { 0.0 }),
+        ior: get("_ri")
+            .or_else(|| get("_ior").map(|ior| ior + 1.0))
+            .unwrap_or(default.ior),
+        transmission: get("_trans").unwrap_or(if kind == "_glass" { weight } else { 0.0 }),
+        emission: get("_emit").unwrap_or(if kind == "_emit" { weight } else { 0.0 }),
+        density: if kind == "_media" {
+            get("_d").unwrap_or(weight)
+        } else {
+            0.0
+        },
+    })
+}
+
+// The palette MagicaVoxel uses when a file has no RGBA chunk: a 6x6x6 color cube followed by red, green,
+// blue and gray ramps
+fn default_palette() -> [[u8; 4]; 256] {
+    let cube = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = [[0; 4]; 256];
+    let mut index = 1;
+
+    for r in cube {
+        for g in cube {
+            for b in cube {
+                if r == 0 && g == 0 && b == 0 {
+                    continue;
+                }
+
+                palette[index] = [r, g, b, 0xff];
+                index += 1;
+            }
+        }
+    }
+
+    for channels in [[1, 0, 0], [0, 1, 0], [0, 0, 1], [1, 1, 1]] {
+        for value in ramp {
+            palette[index] = [
+                value * channels[0],
+                value * channels[1],
+                value * channels[2],
+                0xff,
+            ];
+            index += 1;
+        }
+    }
+
+    palette
+}
+
+// MagicaVoxel models can be at most 256 voxels along each axis, larger scenes are split into tiles
+const MAX_MODEL_SIZE: u32 = 256;
+
+// Palette index 0 is empty space, which leaves 255 usable colors
+const MAX_PALETTE_COLORS: usize = 255;
+
+pub fn write(scene: &Scene) -> Vec<u8> {
+    let (palette, indices) = build_palette(&scene.voxels, MAX_PALETTE_COLORS);
+
+    let tiles = (scene.size.max(UVec3::ONE) + UVec3::splat(MAX_MODEL_SIZE - 1)) / MAX_MODEL_SIZE;
+    let mut models = vec![Vec::new(); (tiles.x * tiles.y * tiles.z) as usize];
+    let tile_index =
+        |tile: UVec3| (tile.x + tile.y * tiles.x + tile.z * tiles.x * tiles.y) as usize;
+
+    // Palette entries can only carry a single material, the first voxel to use an entry decides it
+    let mut palette_materials = HashMap::new();
+
+    for voxel in &scene.voxels {
+        let index = indices[&voxel.color] as u8;
+        let local = voxel.position % MAX_MODEL_SIZE;
+
+        models[tile_index(voxel.position / MAX_MODEL_SIZE)].push([
+            local.x as u8,
+            local.y as u8,
+            local.z as u8,
+            index,
+        ]);
+        palette_materials.entry(index).or_insert(voxel.material);
+    }
+
+    let mut children = Writer::new();
+    let mut placements = Vec::new();
+
+    for z in 0..tiles.z {
+        for y in 0..tiles.y {
+            for x in 0..tiles.x {
+                let tile = UVec3::new(x, y, z);
+                let origin = tile * MAX_MODEL_SIZE;
+                let size = (scene.size.max(UVec3::ONE) - origin).min(UVec3::splat(MAX_MODEL_SIZE));
+                let voxels = &models[tile_index(tile)];
+
+                let mut content = Writer::new();
+                content.write_i32(size.x as i32);
+                content.write_i32(size.y as i32);
+                content.write_i32(size.z as i32);
+                children.write_chunk(b"SIZE", content);
+
+                let mut content = Writer::new();
+                content.write_i32(voxels.len() as i32);
+                for voxel in voxels {
+                    content.bytes.extend_from_slice(voxel);
+                }
+                children.write_chunk(b"XYZI", content);
+
+                // The reader rotates models about their center, so the translation points at the center too
+                placements.push(origin + size / 2);
+            }
+        }
+    }
+
+    // Scene graph: a root transform holding a group with one transform and shape per model
+    let mut content = Writer::new();
+    content.write_i32(0);
+    content.write_dict(&[]);
+    content.write_i32(1);
+    content.write_i32(-1);
+    content.write_i32(-1);
+    content.write_i32(1);
+    content.write_dict(&[]);
+    children.write_chunk(b"nTRN", content);
+
+    let mut content = Writer::new();
+    content.write_i32(1);
+    content.write_dict(&[]);
+    content.write_i32(placements.len() as i32);
+    for model in 0..placements.len() {
+        content.write_i32(2 + 2 * model as i32);
+    }
+    children.write_chunk(b"nGRP", content);
+
+    for (model, translation) in placements.iter().enumerate() {
+        let mut content = Writer::new();
+        content.write_i32(2 + 2 * model as i32);
+        content.write_dict(&[]);
+        content.write_i32(3 + 2 * model as i32);
+        content.write_i32(-1);
+        content.write_i32(0);
+        content.write_i32(1);
+        content.write_dict(&[(
+            "_t",
+            format!("{} {} {}", translation.x, translation.y, translation.z),
+        )]);
+        children.write_chunk(b"nTRN", content);
+
+        let mut content = Writer::new();
+        content.write_i32(3 + 2 * model as i32);
+        content.write_dict(&[]);
+        content.write_i32(1);
+        content.write_i32(model as i32);
+        content.write_dict(&[]);
+        children.write_chunk(b"nSHP", content);
+    }
+
+    let mut content = Writer::new();
+    for index in 1..=256 {
+        content
+            .bytes
+            .extend_from_slice(&palette.get(index - 1).copied().unwrap_or([0; 4]));
+    }
+    children.write_chunk(b"RGBA", content);
+
+    let mut indices = palette_materials.keys().copied().collect::<Vec<_>>();
+    indices.sort_unstable();
+
+    for index in indices {
+        let material = match scene.materials.get(palette_materials[&index] as usize) {
+            Some(material) if palette_materials[&index] != 0 => material,
+            _ => continue,
+        };
+
+        let mut content = Writer::new();
+        content.write_i32(index as i32);
+        // Media have no blend type in MagicaVoxel, so their surface properties only survive in the dictionary
+        let kind = if material.density != 0.0 {
+            "_media"
+        } else {
+            "_blend"
+        };
+
+        content.write_dict(&[
+            ("_type", kind.to_string()),
+            ("_rough", material.roughness.to_string()),
+            ("_metal", material.metalness.to_string()),
+            ("_ri", material.ior.to_string()),
+            ("_trans", material.transmission.to_string()),
+            ("_emit", material.emission.to_string()),
+            ("_d", material.density.to_string()),
+        ]);
+        children.write_chunk(b"MATL", content);
+    }
+
+    let mut file = Writer::new();
+    file.bytes.extend_from_slice(b"VOX ");
+    file.write_i32(150);
+    file.bytes.extend_from_slice(b"MAIN");
+    file.write_i32(0);
+    file.write_i32(children.bytes.len() as i32);
+    file.bytes.extend_from_slice(&children.bytes);
+
+    file.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn empty_model_keeps_its_size() {
+        let scene = Scene {
+            size: UVec3::new(4, 5, 6),
+            voxels: Vec::new(),
+            materials: vec![Material::default()],
+        };
+
+        let read = parse(&write(&scene)).unwrap();
+
+        assert_eq!(read.size, scene.size);
+        assert!(read.voxels.is_empty());
+    }
+
+    #[test]
+    fn write_keeps_size_and_positions() {
+        let scene = Scene::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/assets/scenes/garfield.evox"
+        ))
+        .unwrap();
+
+        let read = parse(&write(&scene)).unwrap();
+        let positions = |scene: &Scene| {
+            scene
+                .voxels
+                .iter()
+                .map(|voxel| voxel.position)
+                .collect::<HashSet<_>>()
+        };
+
+        assert_eq!(read.size, scene.size);
+        assert_eq!(positions(&read), positions(&scene));
+    }
+
+    // A file holding a single model with the given SIZE chunk and voxel count, but no voxels
+    fn model_file(size: [i32; 3], count: i32) -> Vec<u8> {
+        let mut children = Writer::new();
+
+        let mut content = Writer::new();
+        size.iter().for_each(|axis| content.write_i32(*axis));
+        children.write_chunk(b"SIZE", content);
+
+        let mut content = Writer::new();
+        content.write_i32(count);
+        children.write_chunk(b"XYZI", content);
+
+        let mut file = Writer::new();
+        file.bytes.extend_from_slice(b"VOX ");
+        file.write_i32(150);
+        file.bytes.extend_from_slice(b"MAIN");
+        file.write_i32(0);
+        file.write_i32(children.bytes.len() as i32);
+        file.bytes.extend_from_slice(&children.bytes);
+        file.bytes
+    }
+
+    #[test]
+    fn corrupt_sizes_and_counts_are_errors() {
+        assert!(parse(&model_file([2, 2, 2], 0)).is_ok());
+
+        for size in [[0, 2, 2], [-5, 2, 2], [2, 2, i32::MAX], [2, 257, 2]] {
+            assert!(parse(&model_file(size, 0)).is_err(), "size {:?}", size);
+        }
+
+        assert!(parse(&model_file([2, 2, 2], i32::MAX)).is_err());
+    }
+}
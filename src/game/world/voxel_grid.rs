@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::{IVec3, Mat4, UVec3, Vec3};
+
+use super::{Material, Scene};
+
+// The edge length of a chunk in voxels, has to be a power of two so chunks line up with the mip chain on the gpu
+pub const CHUNK_SIZE: u32 = 32;
+
+const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+// A cube of CHUNK_SIZE voxels, colors are stored in x, y, z order
+pub struct Chunk {
+    colors: Vec<[u8; 4]>,
+    materials: Vec<u8>,
+    filled: usize,
+}
+
+impl Chunk {
+    fn new() -> Self {
+        Self {
+            colors: vec![[0; 4]; CHUNK_VOLUME],
+            materials: vec![0; CHUNK_VOLUME],
+            filled: 0,
+        }
+    }
+
+    pub fn colors(&self) -> &[[u8; 4]] {
+        &self.colors
+    }
+
+    // The material index of every voxel, in the same order as the colors
+    pub fn materials(&self) -> &[u8] {
+        &self.materials
+    }
+
+    fn set(&mut self, index: usize, color: [u8; 4], material: u8) {
+        match (self.colors[index][3] != 0, color[3] != 0) {
+            (false, true) => self.filled += 1,
+            (true, false) => self.filled -= 1,
+            _ => (),
+        }
+
+        self.colors[index] = color;
+        self.materials[index] = if color[3] != 0 { material } else { 0 };
+    }
+}
+
+// The voxels of the world, stored on the cpu so game logic can query and modify them. The world is split into
+// chunks that only exist while they contain voxels, so it has no fixed bounds. A voxel is empty when its alpha is
+// zero.
+pub struct VoxelGrid {
+    chunks: HashMap<IVec3, Chunk>,
+    pub materials: Vec<Material>, // The first material is the default diffuse material
+    pub origin: Vec3,             // The world space position of the corner of voxel zero
+    pub voxel_size: f32,          // The edge length of a voxel in world units
+    dirty: HashSet<IVec3>,
+}
+
+impl VoxelGrid {
+    pub fn new() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            materials: vec![Material::default()],
+            origin: Vec3::ZERO,
+            voxel_size: 1.0,
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub fn from_scene(scene: &Scene) -> Self {
+        let mut grid = Self::new();
+        grid.materials = scene.materials.clone();
+
+        for voxel in &scene.voxels {
+            if voxel.position.cmplt(scene.size).all() {
+                grid.set(voxel.position.as_ivec3(), voxel.color, voxel.material);
+            }
+        }
+
+        grid
+    }
+
+    // Maps world space to voxel space, where a voxel spans one unit
+    pub fn world_to_voxel(&self) -> Mat4 {
+        Mat4::from_scale(Vec3::splat(1.0 / self.voxel_size)) * Mat4::from_translation(-self.origin)
+    }
+
+    pub fn voxel_position(&self, world_position: Vec3) -> Vec3 {
+        (world_position - self.origin) / self.voxel_size
+    }
+
+    // The chunk a voxel position lies in
+    pub fn chunk_position(position: IVec3) -> IVec3 {
+        let size = CHUNK_SIZE as i32;
+
+        IVec3::new(
+            position.x.div_euclid(size),
+            position.y.div_euclid(size),
+            position.z.div_euclid(size),
+        )
+    }
+
+    pub fn chunk(&self, chunk_position: IVec3) -> Option<&Chunk> {
+        self.chunks.get(&chunk_position)
+    }
+
+    pub fn set(&mut self, position: IVec3, color: [u8; 4], material: u8) {
+        let (chunk_position, index) = self.locate(position);
+
+        if color[3] == 0 && !self.chunks.contains_key(&chunk_position) {
+            return;
+        }
+
+        let chunk = self.chunks.entry(chunk_position).or_insert_with(Chunk::new);
+        chunk.set(index, color, material);
+
+        if chunk.filled == 0 {
+            self.chunks.remove(&chunk_position);
+        }
+
+        self.dirty.insert(chunk_position);
+    }
+
+    // Iterates over every filled voxel as its position, color and material
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, [u8; 4], u8)> + '_ {
+        self.chunks.iter().flat_map(|(chunk_position, chunk)| {
+            let origin = *chunk_position * CHUNK_SIZE as i32;
+
+            chunk
+                .colors
+                .iter()
+                .zip(chunk.materials.iter())
+                .enumerate()
+                .filter(|(_, (color, _))| color[3] != 0)
+                .map(move |(index, (color, material))| {
+                    let index = index as u32;
+                    let local = UVec3::new(
+                        index % CHUNK_SIZE,
+                        index / CHUNK_SIZE % CHUNK_SIZE,
+                        index / (CHUNK_SIZE * CHUNK_SIZE),
+                    );
+
+                    (origin + local.as_ivec3(), *color, *material)
+                })
+        })
+    }
+
+    // The smallest box around every filled voxel, min inclusive and max exclusive, or none when the grid is empty.
+    // This visits every voxel, so it's meant for one off queries rather than every frame.
+    pub fn bounds(&self) -> Option<(IVec3, IVec3)> {
+        self.iter()
+            .fold(None, |bounds, (position, _, _)| match bounds {
+                Some((min, max)) => Some((min.min(position), max.max(position + IVec3::ONE))),
+                None => Some((position, position + IVec3::ONE)),
+            })
+    }
+
+    // Returns the chunks changed since the last call and resets the tracker, this includes chunks that were removed
+    // because they became empty
+    pub fn take_dirty_chunks(&mut self) -> Vec<IVec3> {
+        self.dirty.drain().collect()
+    }
+
+    fn locate(&self, position: IVec3) -> (IVec3, usize) {
+        let chunk = Self::chunk_position(position);
+        let local = (position - chunk * CHUNK_SIZE as i32).as_uvec3();
+
+        (
+            chunk,
+            (local.x + (local.y + local.z * CHUNK_SIZE) * CHUNK_SIZE) as usize,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_cover_filled_voxels_across_chunks() {
+        let mut grid = VoxelGrid::new();
+        assert_eq!(grid.bounds(), None);
+
+        grid.set(IVec3::new(-3, 5, 40), [255; 4], 0);
+        grid.set(IVec3::new(70, -1, 2), [255; 4], 0);
+        grid.set(IVec3::new(0, 0, 0), [255; 4], 0);
+        grid.set(IVec3::new(1, 1, 1), [255; 4], 0);
+        assert_eq!(
+            grid.bounds(),
+            Some((IVec3::new(-3, -1, 0), IVec3::new(71, 6, 41)))
+        );
+
+        grid.set(IVec3::new(70, -1, 2), [0; 4], 0);
+        assert_eq!(
+            grid.bounds(),
+            Some((IVec3::new(-3, 0, 0), IVec3::new(2, 6, 41)))
+        );
+    }
+}
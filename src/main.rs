@@ -91,6 +91,7 @@ fn main() {
                 };
                 let render_delta = std::time::Instant::now() - last_render_start;
                 context.render_time = render_delta.as_secs_f32() * 1000.0;
+                context.mipmap_time = renderer.mipmap_time();
             }
             Event::MainEventsCleared => {
                 let delta = std::time::Instant::now() - last_frame;
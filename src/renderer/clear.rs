@@ -1,84 +1,64 @@
-use std::{cell::RefCell, convert::TryInto, mem::size_of, num::NonZeroU32, rc::Rc, sync::Arc};
+use std::{cell::RefCell, num::NonZeroU32, rc::Rc};
 
-use crevice::std430::{AsStd430, Std430};
-use futures::lock::Mutex;
-use glam::IVec2;
-use itertools::Itertools;
+use glam::UVec3;
 use wgpu::util::DeviceExt;
-use winit::dpi::PhysicalSize;
 
-use crate::{
-    game::{
-        entity::components::model::{ModelVertex, Vertex},
-        entity::components::Model,
-        World,
-    },
-    renderer::{glsl_loader, RenderContext},
-};
-
-use self::uniforms::Uniforms;
+use crate::renderer::RenderContext;
 
 use super::{texture_atlas::TextureAtlas, ShaderBundle};
 
+// The edge length of a workgroup in clear.comp
+const WORKGROUP_SIZE: u32 = 4;
 
+// Clears boxes of texels in the first level of the dynamic layer, before models that moved are voxelized again
 pub struct Clear {
     compute_pipeline: wgpu::ComputePipeline,
-    compute_bind_group_layout: wgpu::BindGroupLayout,
-    world: Arc<Mutex<World>>,
-    mip_levels: u32,
-    atlas: Rc<RefCell<TextureAtlas>>,
-    bind_groups: Vec<wgpu::BindGroup>,
-    width: i32,
-    height: i32,
-    length: i32,
+    region_bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
 }
 
 impl Clear {
-    pub async fn new(
-        context: &RenderContext,
-        world: Arc<Mutex<World>>,
-        atlas: Rc<RefCell<TextureAtlas>>,
-    ) -> Self {
-        let (width, height, length) = (128, 128, 128);
-
+    pub async fn new(context: &RenderContext, atlas: Rc<RefCell<TextureAtlas>>) -> Self {
         let compute_bind_group_layout =
             context
                 .device
                 .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                    entries: &[
-                        wgpu::BindGroupLayoutEntry {
-                            binding: 0,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            ty: wgpu::BindingType::Texture {
-                                multisampled: false,
-                                view_dimension: wgpu::TextureViewDimension::D3,
-                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
-                            },
-                            count: None,
+                    entries: &[wgpu::BindGroupLayoutEntry {
+                        ty: wgpu::BindingType::StorageTexture {
+                            access: wgpu::StorageTextureAccess::WriteOnly,
+                            format: wgpu::TextureFormat::Rgba32Float,
+                            view_dimension: wgpu::TextureViewDimension::D3,
                         },
-                        wgpu::BindGroupLayoutEntry {
-                            ty: wgpu::BindingType::StorageTexture {
-                                access: wgpu::StorageTextureAccess::WriteOnly,
-                                format: wgpu::TextureFormat::Rgba32Float,
-                                view_dimension: wgpu::TextureViewDimension::D3,
-                            },
-                            binding: 1,
-                            visibility: wgpu::ShaderStages::COMPUTE,
-                            count: None,
-                        },
-                    ],
-                    label: Some("Mipmap Compute Shader Bind Group layout decriptor"),
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        count: None,
+                    }],
+                    label: Some("Clear Compute Shader Bind Group Layout"),
                 });
 
-        let mip_levels =
-            ((((width).min((height).min(length)) as f32).log2()).floor() - 0.0).max(0.0) as u32 - 1;
+        let region_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    }],
+                    label: Some("Clear Region Bind Group Layout"),
+                });
 
         let compute_pipeline_layout =
             context
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: Some("Mipmap Compute Shader Pipeline Layout Descriptor"),
-                    bind_group_layouts: &[&compute_bind_group_layout],
+                    label: Some("Clear Compute Shader Pipeline Layout"),
+                    bind_group_layouts: &[&compute_bind_group_layout, &region_bind_group_layout],
                     push_constant_ranges: &[],
                 });
 
@@ -93,102 +73,96 @@ impl Clear {
             context
                 .device
                 .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-                    label: Some("Compute pipeline"),
+                    label: Some("Clear Compute Pipeline"),
                     layout: Some(&compute_pipeline_layout),
                     module: &compute_shader,
                     entry_point: "main",
                 });
 
-        let atlas_ref = atlas.borrow_mut();
-
-        let views = (0..mip_levels)
-            .map(|mip| {
-                atlas_ref
-                    .get_view_descriptor(
-                        "voxelizer_attachment_world",
-                        {
-                            &wgpu::TextureViewDescriptor {
-                                label: Some("mip"),
-                                format: None,
-                                dimension: None,
-                                aspect: wgpu::TextureAspect::All,
-                                base_mip_level: mip,
-                                mip_level_count: NonZeroU32::new(1),
-                                base_array_layer: 0,
-                                array_layer_count: None,
-                            }
-                        },
-                        context,
-                    )
-                    .unwrap()
-            })
-            .collect::<Vec<_>>();
+        let bind_group = context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Clear Compute Shader Bind Group"),
+                layout: &compute_bind_group_layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &atlas
+                            .borrow()
+                            .get_view_descriptor(
+                                "voxelizer_attachment_dynamic",
+                                &wgpu::TextureViewDescriptor {
+                                    label: Some("mip"),
+                                    format: None,
+                                    dimension: Some(wgpu::TextureViewDimension::D3),
+                                    aspect: wgpu::TextureAspect::All,
+                                    base_mip_level: 0,
+                                    mip_level_count: NonZeroU32::new(1),
+                                    base_array_layer: 0,
+                                    array_layer_count: None,
+                                },
+                                context,
+                            )
+                            .unwrap(),
+                    ),
+                }],
+            });
+
+        Self {
+            compute_pipeline,
+            region_bind_group_layout,
+            bind_group,
+        }
+    }
 
-        let bind_groups = (1..mip_levels)
-            .map(|level| {
-                let texture_view = views.get(level as usize - 1).unwrap();
+    // Clears every box, given as the origin and size of the box in texels
+    pub async fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        context: &RenderContext,
+        boxes: &[(UVec3, UVec3)],
+    ) {
+        if boxes.is_empty() {
+            return;
+        }
 
-                let destination_texture = views.get(level as usize).unwrap();
+        let region_bind_groups = boxes
+            .iter()
+            .map(|(origin, size)| {
+                let buffer = context
+                    .device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Clear Region"),
+                        contents: bytemuck::cast_slice(&[
+                            origin.x, origin.y, origin.z, 0, size.x, size.y, size.z, 0,
+                        ]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    });
 
                 context
                     .device
                     .create_bind_group(&wgpu::BindGroupDescriptor {
-                        label: Some(&format!("Mipmap Compute Shader Bind Group level {}", level)),
-                        layout: &compute_bind_group_layout,
-                        entries: &[
-                            wgpu::BindGroupEntry {
-                                binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&texture_view),
-                            },
-                            wgpu::BindGroupEntry {
-                                binding: 1,
-                                resource: wgpu::BindingResource::TextureView(&destination_texture),
-                            },
-                        ],
+                        label: Some("Clear Region Bind Group"),
+                        layout: &self.region_bind_group_layout,
+                        entries: &[wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: buffer.as_entire_binding(),
+                        }],
                     })
             })
             .collect::<Vec<_>>();
 
-        Self {
-            compute_pipeline,
-            world,
-            mip_levels,
-            atlas: atlas.clone(),
-            compute_bind_group_layout,
-            bind_groups,
-            width,
-            height,
-            length,
-        }
-    }
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Clear Compute Pass"),
+        });
 
-    pub async fn render(&mut self, context: &RenderContext) {
-        let mut encoder = context
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-
-        {
-            let mut compute_pass =
-                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-
-            for level in 1..self.mip_levels as usize {
-                let div_factor = (2i32).pow(level as u32);
-                let (width, height, length) = (
-                    self.width / div_factor,
-                    self.height / div_factor,
-                    self.length / div_factor,
-                );
-
-                compute_pass.set_pipeline(&self.compute_pipeline);
-                compute_pass.set_bind_group(0, &self.bind_groups.get(level - 1).unwrap(), &[]);
-                compute_pass.dispatch(
-                    ((width as u32)).max(1),
-                    ((length as u32)).max(1),
-                    ((height as u32)).max(1),
-                );
-            }
-        }
+        compute_pass.set_pipeline(&self.compute_pipeline);
+        compute_pass.set_bind_group(0, &self.bind_group, &[]);
 
-        context.queue.submit(Some(encoder.finish()));
+        for ((_, size), region_bind_group) in boxes.iter().zip(&region_bind_groups) {
+            compute_pass.set_bind_group(1, region_bind_group, &[]);
+            let workgroups = (*size + UVec3::splat(WORKGROUP_SIZE - 1)) / WORKGROUP_SIZE;
+            compute_pass.dispatch(workgroups.x, workgroups.y, workgroups.z);
+        }
     }
 }
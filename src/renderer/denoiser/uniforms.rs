@@ -47,7 +47,10 @@ impl Uniforms {
             .expect("ERROR: expected resource not found");
 
         self.inverse_past_camera_matrix = Mat4::from(self.camera_matrix).inverse().into();
-        self.camera_matrix = player.transform.as_matrix().into();
+        // Rays are traced in voxel space
+        self.camera_matrix = (world_lock.voxel_grid.as_ref().unwrap().world_to_voxel()
+            * player.transform.as_matrix())
+        .into();
         self.resolution = IVec2::new(
             player.camera.size.width as i32,
             player.camera.size.height as i32,
@@ -55,10 +58,17 @@ impl Uniforms {
         .into();
         self.focal_length = player.camera.focal_length();
         self.frame_count = player.camera.frame_count as i32;
-        self.enable_filtering = config
+        // Debug views and cone traced frames are passed through untouched
+        self.enable_filtering = (config
             .get_var("renderer_denoiser_enable_filtering")
             .unwrap()
-            .as_i32();
+            .as_bool()
+            && config.get_var("renderer_debug_view").unwrap().as_string() == "final"
+            && config
+                .get_var("renderer_raytracer_gi_mode")
+                .unwrap()
+                .as_string()
+                == "path_tracing") as i32;
         self.reprojection_percent = config
             .get_var("renderer_denoiser_reprojection_percent")
             .unwrap()
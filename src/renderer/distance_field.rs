@@ -0,0 +1,331 @@
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+
+use futures::lock::Mutex;
+use glam::{IVec3, UVec3};
+use wgpu::util::DeviceExt;
+
+use crate::game::{world::CHUNK_SIZE, World};
+
+use super::{
+    texture_atlas::TextureAtlas, world_streamer::WorldStreamer, RenderContext, ShaderBundle,
+};
+
+// A brick is covered by a single texel of this mip level of the chunk pool, 4 voxels wide
+pub const BRICK_LEVEL: u32 = 2;
+
+// Distances are counted in bricks and clamped to this, it bounds the work of every pass
+const MAX_DISTANCE: u32 = 8;
+
+// The edge length of a workgroup in distance_field.comp
+const WORKGROUP_SIZE: u32 = 4;
+
+// Builds the Chebyshev distance from every brick in the window of streamed chunks to the closest filled brick. The
+// raytracer uses it to jump over empty space when the distance field acceleration is selected.
+pub struct DistanceField {
+    compute_pipeline: wgpu::ComputePipeline,
+    world_bind_group: wgpu::BindGroup,
+    pass_bind_groups: Vec<wgpu::BindGroup>,
+    size: UVec3,
+    world: Arc<Mutex<World>>,
+    built_window_origin: Option<IVec3>, // Where the window was when the field was last built
+}
+
+impl DistanceField {
+    pub async fn new(
+        context: &RenderContext,
+        world: Arc<Mutex<World>>,
+        atlas: Rc<RefCell<TextureAtlas>>,
+    ) -> Self {
+        let window_size = atlas
+            .borrow()
+            .get_info("world_streamer_indirection", context)
+            .unwrap()
+            .size;
+        let size =
+            UVec3::new(window_size.0, window_size.1, window_size.2) * (CHUNK_SIZE >> BRICK_LEVEL);
+
+        let descriptor = |label| wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: size.z,
+            },
+            mip_level_count: 1,
+            label: Some(label),
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D3,
+            format: wgpu::TextureFormat::R32Uint,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::STORAGE_BINDING,
+        };
+
+        // Every pass needs the result of the one before it around the texels it writes, so none of them share a texture
+        let x_texture = context
+            .device
+            .create_texture(&descriptor("Distance Field X"));
+        let xy_texture = context
+            .device
+            .create_texture(&descriptor("Distance Field XY"));
+
+        atlas.borrow_mut().register_from_descriptor(
+            "distance_field",
+            descriptor("Distance Field"),
+            context,
+        );
+
+        let world_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
+                    label: Some("Distance Field World Bind Group Layout"),
+                });
+
+        let pass_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::StorageTexture {
+                                access: wgpu::StorageTextureAccess::WriteOnly,
+                                format: wgpu::TextureFormat::R32Uint,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
+                    label: Some("Distance Field Pass Bind Group Layout"),
+                });
+
+        let compute_pipeline_layout =
+            context
+                .device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some("Distance Field Pipeline Layout"),
+                    bind_group_layouts: &[&world_bind_group_layout, &pass_bind_group_layout],
+                    push_constant_ranges: &[],
+                });
+
+        let compute_shader;
+
+        unsafe {
+            compute_shader = ShaderBundle::compute_from_path("distance_field")
+                .create_compute_shader_module_spirv(context);
+        }
+
+        let compute_pipeline =
+            context
+                .device
+                .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                    label: Some("Distance Field Pipeline"),
+                    layout: Some(&compute_pipeline_layout),
+                    module: &compute_shader,
+                    entry_point: "main",
+                });
+
+        let atlas_ref = atlas.borrow();
+
+        let world_bind_group = context
+            .device
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Distance Field World Bind Group"),
+                layout: &world_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas_ref
+                                .get_view("voxelizer_attachment_world", context)
+                                .unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas_ref
+                                .get_view("voxelizer_attachment_dynamic", context)
+                                .unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas_ref
+                                .get_view("world_streamer_indirection", context)
+                                .unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: atlas_ref
+                            .get_buffer("world_streamer_uniforms", context)
+                            .unwrap()
+                            .as_entire_binding(),
+                    },
+                ],
+            });
+
+        let views = [
+            x_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            xy_texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            atlas_ref.get_view("distance_field", context).unwrap(),
+        ];
+
+        // The first pass reads the chunk pool instead of a source texture, the final field is bound in its place
+        let pass_bind_groups = (0..3)
+            .map(|axis| {
+                let source = if axis == 0 { 2 } else { axis - 1 };
+
+                let buffer = context
+                    .device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Distance Field Pass"),
+                        contents: bytemuck::cast_slice(&[
+                            axis as i32,
+                            BRICK_LEVEL as i32,
+                            MAX_DISTANCE as i32,
+                            0,
+                        ]),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    });
+
+                context
+                    .device
+                    .create_bind_group(&wgpu::BindGroupDescriptor {
+                        label: Some(&format!("Distance Field Pass {} Bind Group", axis)),
+                        layout: &pass_bind_group_layout,
+                        entries: &[
+                            wgpu::BindGroupEntry {
+                                binding: 0,
+                                resource: wgpu::BindingResource::TextureView(&views[source]),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 1,
+                                resource: wgpu::BindingResource::TextureView(&views[axis]),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: buffer.as_entire_binding(),
+                            },
+                        ],
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        Self {
+            compute_pipeline,
+            world_bind_group,
+            pass_bind_groups,
+            size,
+            world,
+            built_window_origin: None,
+        }
+    }
+
+    // Rebuilds the field when the window moved or the voxels in it changed, and only while it is in use
+    pub async fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        streamer: &WorldStreamer,
+        voxels_changed: bool,
+    ) {
+        let acceleration = self
+            .world
+            .lock()
+            .await
+            .config
+            .as_ref()
+            .unwrap()
+            .get_var("renderer_raytracer_acceleration")
+            .unwrap()
+            .as_string();
+
+        if acceleration != "distance_field" {
+            self.built_window_origin = None;
+            return;
+        }
+
+        if self.built_window_origin == Some(streamer.window_origin()) && !voxels_changed {
+            return;
+        }
+
+        self.built_window_origin = Some(streamer.window_origin());
+
+        let workgroups = (self.size + UVec3::splat(WORKGROUP_SIZE - 1)) / WORKGROUP_SIZE;
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Distance Field Compute Pass"),
+        });
+
+        compute_pass.set_pipeline(&self.compute_pipeline);
+        compute_pass.set_bind_group(0, &self.world_bind_group, &[]);
+
+        for pass_bind_group in &self.pass_bind_groups {
+            compute_pass.set_bind_group(1, pass_bind_group, &[]);
+            compute_pass.dispatch(workgroups.x, workgroups.y, workgroups.z);
+        }
+    }
+}
@@ -1,30 +1,53 @@
-use std::{cell::RefCell, num::NonZeroU32, rc::Rc, sync::Arc};
+use std::{
+    cell::RefCell,
+    future::Future,
+    num::{NonZeroU32, NonZeroU64},
+    pin::Pin,
+    rc::Rc,
+    sync::Arc,
+};
 
-use futures::lock::Mutex;
+use futures::{lock::Mutex, FutureExt};
+use glam::UVec3;
 
 use crate::{game::World, renderer::RenderContext};
 
 use super::{texture_atlas::TextureAtlas, ShaderBundle};
 
+// Levels are built in pairs, every dispatch reduces a level into the next two through shared memory
+const LEVELS_PER_PASS: u32 = 2;
+
+// The edge length of a workgroup in mipmap.comp
+const WORKGROUP_SIZE: u32 = 4;
+
+// The size of Region in mipmap.comp
+const REGION_SIZE: u32 = 32;
+
 pub struct Mipmapper {
     compute_pipeline: wgpu::ComputePipeline,
     compute_bind_group_layout: wgpu::BindGroupLayout,
+    region_bind_group_layout: wgpu::BindGroupLayout,
     world: Arc<Mutex<World>>,
     mip_levels: u32,
+    size: UVec3,
+    reduction: Option<String>,
+    reduction_index: i32,
     atlas: Rc<RefCell<TextureAtlas>>,
-    bind_groups: Vec<wgpu::BindGroup>,
-    width: i32,
-    height: i32,
-    length: i32,
+    passes: Vec<(u32, u32, wgpu::BindGroup)>, // The source level, the levels built from it and their bind group
+    region_buffer: wgpu::Buffer, // Holds the region of every dispatch, each bound with its own dynamic offset
+    region_bind_group: wgpu::BindGroup,
+    region_capacity: usize,
+    region_stride: u32,
+    timestamps: Option<Timestamps>, // Only there when the device supports timestamp queries
 }
-
 impl Mipmapper {
     pub async fn new(
         context: &RenderContext,
         world: Arc<Mutex<World>>,
         atlas: Rc<RefCell<TextureAtlas>>,
+        texture: &str,
     ) -> Self {
-        let (width, height, length) = (128, 128, 128);
+        let info = atlas.borrow().get_info(texture, context).unwrap();
 
         let compute_bind_group_layout =
             context
@@ -51,19 +74,45 @@ impl Mipmapper {
                             visibility: wgpu::ShaderStages::COMPUTE,
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            ty: wgpu::BindingType::StorageTexture {
+                                access: wgpu::StorageTextureAccess::WriteOnly,
+                                format: wgpu::TextureFormat::Rgba32Float,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                            },
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::COMPUTE,
+                            count: None,
+                        },
                     ],
                     label: Some("Mipmap Compute Shader Bind Group layout decriptor"),
                 });
 
-        let mip_levels =
-            ((((width).min((height).min(length)) as f32).log2()).floor() - 0.0).max(0.0) as u32 - 1;
+        let region_bind_group_layout =
+            context
+                .device
+                .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                    entries: &[wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: true,
+                            min_binding_size: NonZeroU64::new(REGION_SIZE as u64),
+                        },
+                        count: None,
+                    }],
+                    label: Some("Mipmap Region Bind Group Layout"),
+                });
+
+        let mip_levels = info.mip_levels;
 
         let compute_pipeline_layout =
             context
                 .device
                 .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                     label: Some("Mipmap Compute Shader Pipeline Layout Descriptor"),
-                    bind_group_layouts: &[&compute_bind_group_layout],
+                    bind_group_layouts: &[&compute_bind_group_layout, &region_bind_group_layout],
                     push_constant_ranges: &[],
                 });
 
@@ -90,7 +139,7 @@ impl Mipmapper {
             .map(|mip| {
                 atlas_ref
                     .get_view_descriptor(
-                        "voxelizer_attachment_world",
+                        texture,
                         {
                             &wgpu::TextureViewDescriptor {
                                 label: Some("mip"),
@@ -109,13 +158,36 @@ impl Mipmapper {
             })
             .collect::<Vec<_>>();
 
-        let bind_groups = (1..mip_levels)
+        // A pass building a single level still needs something bound as its second destination
+        let unused_view = context
+            .device
+            .create_texture(&wgpu::TextureDescriptor {
+                label: Some("Mipmap Unused Destination"),
+                size: wgpu::Extent3d {
+                    width: 1,
+                    height: 1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING,
+            })
+            .create_view(&wgpu::TextureViewDescriptor::default());
+
+        let passes = (0..mip_levels.saturating_sub(1))
+            .step_by(LEVELS_PER_PASS as usize)
             .map(|level| {
-                let texture_view = views.get(level as usize - 1).unwrap();
+                let levels = LEVELS_PER_PASS.min(mip_levels - 1 - level);
 
-                let destination_texture = views.get(level as usize).unwrap();
+                let next_view = if levels > 1 {
+                    &views[level as usize + 2]
+                } else {
+                    &unused_view
+                };
 
-                context
+                let bind_group = context
                     .device
                     .create_bind_group(&wgpu::BindGroupDescriptor {
                         label: Some(&format!("Mipmap Compute Shader Bind Group level {}", level)),
@@ -123,57 +195,313 @@ impl Mipmapper {
                         entries: &[
                             wgpu::BindGroupEntry {
                                 binding: 0,
-                                resource: wgpu::BindingResource::TextureView(&texture_view),
+                                resource: wgpu::BindingResource::TextureView(
+                                    &views[level as usize],
+                                ),
                             },
                             wgpu::BindGroupEntry {
                                 binding: 1,
-                                resource: wgpu::BindingResource::TextureView(&destination_texture),
+                                resource: wgpu::BindingResource::TextureView(
+                                    &views[level as usize + 1],
+                                ),
+                            },
+                            wgpu::BindGroupEntry {
+                                binding: 2,
+                                resource: wgpu::BindingResource::TextureView(next_view),
                             },
                         ],
-                    })
+                    });
+
+                (level, levels, bind_group)
             })
             .collect::<Vec<_>>();
 
+        // Regions are placed at the offset alignment of the device, the buffer grows when a render needs more
+        let alignment = context.device.limits().min_uniform_buffer_offset_alignment;
+        let region_stride = (REGION_SIZE + alignment - 1) / alignment * alignment;
+        let region_capacity = 64;
+        let (region_buffer, region_bind_group) = Self::create_regions(
+            context,
+            &region_bind_group_layout,
+            region_stride,
+            region_capacity,
+        );
+
         Self {
             compute_pipeline,
             world,
             mip_levels,
+            size: UVec3::new(info.size.0, info.size.1, info.size.2),
+            reduction: None,
+            reduction_index: 0,
             atlas: atlas.clone(),
             compute_bind_group_layout,
-            bind_groups,
-            width,
-            height,
-            length,
+            region_bind_group_layout,
+            passes,
+            region_buffer,
+            region_bind_group,
+            region_capacity,
+            region_stride,
+            timestamps: Timestamps::new(context),
+        }
+    }
+
+    // Rebuilds the mips covering the given boxes of the first level, given as the origin and size of the box in texels.
+    // Everything is rebuilt when the reduction was changed.
+    pub async fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        context: &RenderContext,
+        boxes: &[(UVec3, UVec3)],
+    ) {
+        let reduction = self
+            .world
+            .lock()
+            .await
+            .config
+            .as_ref()
+            .unwrap()
+            .get_var("renderer_mipmap_reduction")
+            .unwrap()
+            .as_string();
+        let whole = [(UVec3::ZERO, self.size)];
+
+        let boxes = if self.reduction.as_ref() != Some(&reduction) {
+            self.reduction_index = Self::reduction_index(&reduction);
+            self.reduction = Some(reduction);
+            &whole[..]
+        } else {
+            boxes
+        };
+
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            timestamps.read(context);
+        }
+
+        if boxes.is_empty() {
+            return;
+        }
+
+        // A texel of a level covers two texels of the level below, so the box grows to cover partially changed texels.
+        // It is aligned to whole workgroups so the second level of a pass only reads texels of its own workgroup.
+        let dispatches = self
+            .passes
+            .iter()
+            .enumerate()
+            .flat_map(|(pass, (level, _, _))| {
+                let level = level + 1;
+
+                boxes.iter().map(move |(origin, size)| {
+                    let start = (*origin >> level) / WORKGROUP_SIZE * WORKGROUP_SIZE;
+                    let end = (*origin + *size + UVec3::splat((1 << level) - 1)) >> level;
+                    let workgroups =
+                        (end - start + UVec3::splat(WORKGROUP_SIZE - 1)) / WORKGROUP_SIZE;
+
+                    (pass, start, workgroups)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if dispatches.len() > self.region_capacity {
+            self.region_capacity = dispatches.len().next_power_of_two();
+            let (region_buffer, region_bind_group) = Self::create_regions(
+                context,
+                &self.region_bind_group_layout,
+                self.region_stride,
+                self.region_capacity,
+            );
+            self.region_buffer = region_buffer;
+            self.region_bind_group = region_bind_group;
+        }
+
+        let stride = self.region_stride as usize;
+        let mut regions = vec![0u8; dispatches.len() * stride];
+
+        for (index, (pass, start, _)) in dispatches.iter().enumerate() {
+            let levels = self.passes[*pass].1 as i32;
+            let region = [
+                start.x as i32,
+                start.y as i32,
+                start.z as i32,
+                levels,
+                self.reduction_index,
+                0,
+                0,
+                0,
+            ];
+
+            regions[index * stride..index * stride + REGION_SIZE as usize]
+                .copy_from_slice(bytemuck::cast_slice(&region));
+        }
+
+        context.queue.write_buffer(&self.region_buffer, 0, &regions);
+
+        // The buffer of the timestamps can't be written while it's being read back from an earlier frame
+        let timestamps = self
+            .timestamps
+            .as_mut()
+            .filter(|timestamps| timestamps.mapping.is_none());
+
+        if let Some(timestamps) = &timestamps {
+            encoder.write_timestamp(&timestamps.query_set, 0);
+        }
+
+        // Passes are recorded in order, so every pass reads the levels written by the one before it
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Mipmap Compute Pass"),
+        });
+
+        compute_pass.set_pipeline(&self.compute_pipeline);
+
+        for (index, (pass, _, workgroups)) in dispatches.iter().enumerate() {
+            compute_pass.set_bind_group(0, &self.passes[*pass].2, &[]);
+            compute_pass.set_bind_group(
+                1,
+                &self.region_bind_group,
+                &[index as u32 * self.region_stride],
+            );
+            compute_pass.dispatch(workgroups.x, workgroups.y, workgroups.z);
         }
+
+        drop(compute_pass);
+
+        if let Some(timestamps) = timestamps {
+            encoder.write_timestamp(&timestamps.query_set, 1);
+            encoder.resolve_query_set(&timestamps.query_set, 0..2, &timestamps.buffer, 0);
+            timestamps.written = true;
+        }
+    }
+
+    // Starts reading back the timestamps written by the last render, has to be called after its encoder was submitted
+    pub fn map_timestamps(&mut self) {
+        if let Some(timestamps) = self.timestamps.as_mut() {
+            timestamps.map();
+        }
+    }
+
+    // The milliseconds the gpu took for the last measured rebuild, none without timestamp queries
+    pub fn gpu_time(&self) -> Option<f32> {
+        self.timestamps.as_ref().map(|timestamps| timestamps.time)
     }
 
-    pub async fn render(&mut self, context: &RenderContext) {
-        let mut encoder = context
+    fn create_regions(
+        context: &RenderContext,
+        layout: &wgpu::BindGroupLayout,
+        stride: u32,
+        capacity: usize,
+    ) -> (wgpu::Buffer, wgpu::BindGroup) {
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Mipmap Regions"),
+            size: stride as wgpu::BufferAddress * capacity as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = context
             .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+            .create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Mipmap Region Bind Group"),
+                layout,
+                entries: &[wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &buffer,
+                        offset: 0,
+                        size: NonZeroU64::new(REGION_SIZE as u64),
+                    }),
+                }],
+            });
 
+        (buffer, bind_group)
+    }
+
+    // Matches the REDUCTION defines in mipmap.comp
+    fn reduction_index(reduction: &str) -> i32 {
+        match reduction {
+            "average" => 0,
+            "majority" => 1,
+            "occupancy" => 2,
+            _ => {
+                eprintln!("unknown mipmap reduction {}, averaging instead", reduction);
+                0
+            }
+        }
+    }
+}
+
+type Mapping = Pin<Box<dyn Future<Output = Result<(), wgpu::BufferAsyncError>>>>;
+
+// Times the compute pass on the gpu with a timestamp before and after it. The timestamps are only there once the gpu
+// finished the frame, so they are mapped without waiting and the time shows up a few frames late.
+struct Timestamps {
+    query_set: wgpu::QuerySet,
+    buffer: wgpu::Buffer,
+    period: f32,   // The nanoseconds of a timestamp tick
+    written: bool, // The last render wrote timestamps that haven't been mapped yet
+    mapping: Option<Mapping>,
+    time: f32,
+}
+
+impl Timestamps {
+    fn new(context: &RenderContext) -> Option<Self> {
+        if !context
+            .device
+            .features()
+            .contains(wgpu::Features::TIMESTAMP_QUERY)
         {
-            let mut compute_pass =
-                encoder.begin_compute_pass(&wgpu::ComputePassDescriptor { label: None });
-
-            for level in 1..self.mip_levels as usize {
-                let div_factor = (2i32).pow(level as u32);
-                let (width, height, length) = (
-                    self.width / div_factor,
-                    self.height / div_factor,
-                    self.length / div_factor,
-                );
-
-                compute_pass.set_pipeline(&self.compute_pipeline);
-                compute_pass.set_bind_group(0, &self.bind_groups.get(level - 1).unwrap(), &[]);
-                compute_pass.dispatch(
-                    (width as u32).max(1),
-                    (length as u32).max(1),
-                    (height as u32).max(1),
-                );
+            return None;
+        }
+
+        Some(Self {
+            query_set: context.device.create_query_set(&wgpu::QuerySetDescriptor {
+                label: Some("Mipmap Timestamps"),
+                ty: wgpu::QueryType::Timestamp,
+                count: 2,
+            }),
+            buffer: context.device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Mipmap Timestamps"),
+                size: 2 * wgpu::QUERY_SIZE as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            period: context.queue.get_timestamp_period(),
+            written: false,
+            mapping: None,
+            time: 0.0,
+        })
+    }
+
+    fn map(&mut self) {
+        if self.written {
+            self.written = false;
+            self.mapping = Some(Box::pin(
+                self.buffer.slice(..).map_async(wgpu::MapMode::Read),
+            ));
+        }
+    }
+
+    // Picks up the time of an earlier frame if the gpu is done with it
+    fn read(&mut self, context: &RenderContext) {
+        let mapping = match self.mapping.as_mut() {
+            Some(mapping) => mapping,
+            None => return,
+        };
+
+        context.device.poll(wgpu::Maintain::Poll);
+
+        match mapping.as_mut().now_or_never() {
+            Some(Ok(())) => {
+                let range = self.buffer.slice(..).get_mapped_range();
+                let ticks: &[u64] = bytemuck::cast_slice(&range);
+                self.time = ticks[1].saturating_sub(ticks[0]) as f32 * self.period / 1_000_000.0;
+
+                drop(range);
+                self.buffer.unmap();
             }
+            Some(Err(why)) => eprintln!("unable to read the mipmap timestamps: {}", why),
+            None => return,
         }
 
-        context.queue.submit(Some(encoder.finish()));
+        self.mapping = None;
     }
 }
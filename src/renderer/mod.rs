@@ -4,14 +4,16 @@ use futures::lock::Mutex;
 use wgpu::{util::DeviceExt, CommandEncoder};
 use winit::{dpi::PhysicalSize, window};
 
-use crate::game::World;
+use crate::game::{world::CHUNK_SIZE, World};
 
 pub use glsl_loader::ShaderBundle;
 pub use render_context::RenderContext;
 
 use self::texture_atlas::TextureAtlas;
 
+pub mod clear;
 pub mod denoiser; // Designing a render graph system would be beneficial to this code
+pub mod distance_field;
 pub mod glsl_loader;
 pub mod gui_renderer;
 pub mod mipmapper;
@@ -21,6 +23,8 @@ pub mod render_context;
 pub mod texture_atlas;
 pub mod texture_renderer;
 pub mod voxelizer;
+pub mod world_exporter;
+pub mod world_streamer;
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
@@ -80,7 +84,12 @@ pub struct Renderer {
     texture_renderer: texture_renderer::TextureRenderer,
     model_renderer: model_renderer::ModelRenderer,
     voxelizer: voxelizer::Voxelizer,
-    mipmapper: mipmapper::Mipmapper,
+    world_streamer: world_streamer::WorldStreamer,
+    world_exporter: world_exporter::WorldExporter,
+    export_requested: bool,
+    static_mipmapper: mipmapper::Mipmapper,
+    dynamic_mipmapper: mipmapper::Mipmapper,
+    distance_field: distance_field::DistanceField,
     gui: gui_renderer::Gui,
     world: Arc<Mutex<World>>,
     atlas: Rc<RefCell<TextureAtlas>>,
@@ -106,9 +115,31 @@ impl Renderer {
 
         context.surface.configure(&context.device, &surface_config);
 
+        let world_streamer =
+            world_streamer::WorldStreamer::new(context, world.clone(), atlas.clone()).await;
+
         let voxelizer = voxelizer::Voxelizer::new(context, world.clone(), atlas.clone()).await;
 
-        let mipmapper = mipmapper::Mipmapper::new(context, world.clone(), atlas.clone()).await;
+        let world_exporter = world_exporter::WorldExporter::new(world.clone(), atlas.clone()).await;
+
+        let static_mipmapper = mipmapper::Mipmapper::new(
+            context,
+            world.clone(),
+            atlas.clone(),
+            "voxelizer_attachment_world",
+        )
+        .await;
+
+        let dynamic_mipmapper = mipmapper::Mipmapper::new(
+            context,
+            world.clone(),
+            atlas.clone(),
+            "voxelizer_attachment_dynamic",
+        )
+        .await;
+
+        let distance_field =
+            distance_field::DistanceField::new(context, world.clone(), atlas.clone()).await;
 
         let raytracer =
             raytracer::Raytracer::new(context, world.clone(), &surface_config, atlas.clone()).await;
@@ -150,7 +181,12 @@ impl Renderer {
             gui,
             model_renderer,
             voxelizer,
-            mipmapper,
+            world_streamer,
+            world_exporter,
+            export_requested: false,
+            static_mipmapper,
+            dynamic_mipmapper,
+            distance_field,
         }
     }
 
@@ -185,7 +221,21 @@ impl Renderer {
 
     pub fn input(&mut self, event: &winit::event::WindowEvent) -> bool {
         // This function should only be used for accepting debug commands for the renderer
-        false
+        match event {
+            winit::event::WindowEvent::KeyboardInput {
+                input:
+                    winit::event::KeyboardInput {
+                        state: winit::event::ElementState::Pressed,
+                        virtual_keycode: Some(winit::event::VirtualKeyCode::F12),
+                        ..
+                    },
+                ..
+            } => {
+                self.export_requested = true;
+                true
+            }
+            _ => false,
+        }
     }
 
     pub fn update(&mut self) {
@@ -201,9 +251,45 @@ impl Renderer {
                 label: Some("Render Encoder"),
             });
 
-        self.voxelizer.render(&mut encoder, &context).await;
+        self.world_streamer.render(&context).await;
+
+        let uploaded_boxes = self
+            .world_streamer
+            .take_uploaded_chunks()
+            .into_iter()
+            .flat_map(|chunk_position| {
+                let min = chunk_position * CHUNK_SIZE as i32;
+                self.world_streamer.pool_boxes(min, min + CHUNK_SIZE as i32)
+            })
+            .collect::<Vec<_>>();
+        let streamed_chunks = self.world_streamer.take_streamed_chunks();
+
+        // Nothing is voxelized or mipmapped again unless a model or chunk changed
+        self.static_mipmapper
+            .render(&mut encoder, &context, &uploaded_boxes)
+            .await;
+
+        let changed_boxes = self
+            .voxelizer
+            .render(
+                &mut encoder,
+                &context,
+                &self.world_streamer,
+                &streamed_chunks,
+            )
+            .await;
+
+        self.dynamic_mipmapper
+            .render(&mut encoder, &context, &changed_boxes)
+            .await;
 
-        self.mipmapper.render(context).await;
+        self.distance_field
+            .render(
+                &mut encoder,
+                &self.world_streamer,
+                !uploaded_boxes.is_empty() || !changed_boxes.is_empty(),
+            )
+            .await;
 
         self.raytracer
             .render(&mut encoder, context, &self.vertex_buffer)
@@ -261,9 +347,18 @@ impl Renderer {
 
         // submit will accept anything that implements IntoIter
         context.queue.submit(std::iter::once(encoder.finish()));
+        self.static_mipmapper.map_timestamps();
+        self.dynamic_mipmapper.map_timestamps();
 
         frame.present();
 
+        if self.export_requested {
+            self.export_requested = false;
+            self.world_exporter
+                .export(context, &self.world_streamer)
+                .await;
+        }
+
         self.world
             .lock()
             .await
@@ -279,4 +374,9 @@ impl Renderer {
     pub fn size(&self) -> PhysicalSize<u32> {
         self.size
     }
+
+    // The milliseconds the gpu spent on the last measured rebuild of both mipmappers, none without timestamp queries
+    pub fn mipmap_time(&self) -> Option<f32> {
+        Some(self.static_mipmapper.gpu_time()? + self.dynamic_mipmapper.gpu_time()?)
+    }
 }
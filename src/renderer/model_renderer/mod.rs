@@ -234,6 +234,11 @@ impl ModelRenderer {
             // render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
 
             let components = world.get_components::<Model>();
+            // Without a grid there's no world scale yet, so models keep their own size
+            let voxel_size = world
+                .voxel_grid
+                .as_ref()
+                .map_or(1.0, |grid| grid.voxel_size);
 
             render_pass.set_pipeline(&self.render_pipeline);
             render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
@@ -242,7 +247,7 @@ impl ModelRenderer {
 
             for model in components.clone() {
                 self.uniforms
-                    .update_model_matrix(model.transform.model_matrix())
+                    .update_model_matrix(model.model_matrix(voxel_size))
                     .await;
                 context.queue.write_buffer(
                     &self.uniforms_buffer,
@@ -0,0 +1,107 @@
+use crevice::std430::AsStd430;
+use glam::{Vec2, Vec3};
+
+use crate::game::{
+    entity::components::{Light, LightKind},
+    world::VoxelGrid,
+    World,
+};
+
+// The lights storage buffer has room for this many, the rest are ignored
+pub const MAX_LIGHTS: usize = 64;
+
+// Mirrors Light in raytrace.frag, everything is in voxel space
+#[derive(Copy, Clone, Debug, AsStd430)]
+pub struct GpuLight {
+    position: mint::Vector3<f32>,
+    kind: i32, // 0 for point, 1 for spot and 2 for area lights
+    direction: mint::Vector3<f32>,
+    cos_angle: f32,            // The cosine of the half angle of a spot light cone
+    color: mint::Vector3<f32>, // Premultiplied by the intensity
+    size: mint::Vector2<f32>,
+}
+
+impl GpuLight {
+    pub fn new(light: &Light, grid: &VoxelGrid) -> Self {
+        let (kind, cos_angle, size) = match light.kind {
+            LightKind::Point => (0, -1.0, Vec2::ZERO),
+            LightKind::Spot { angle } => (1, angle.cos(), Vec2::ZERO),
+            LightKind::Area { size } => (2, -1.0, size / grid.voxel_size),
+        };
+
+        // Point and spot lights fall off with the squared distance, which is shorter in world units than in voxels.
+        // The radiance of an area light doesn't depend on the scale.
+        let intensity = match light.kind {
+            LightKind::Area { .. } => light.intensity,
+            _ => light.intensity / (grid.voxel_size * grid.voxel_size),
+        };
+
+        Self {
+            position: grid.voxel_position(light.position).into(),
+            kind,
+            direction: light.direction.normalize_or_zero().into(),
+            cos_angle,
+            color: (light.color * intensity).into(),
+            size: size.into(),
+        }
+    }
+
+    pub fn empty() -> Self {
+        Self {
+            position: Vec3::ZERO.into(),
+            kind: 0,
+            direction: Vec3::ZERO.into(),
+            cos_angle: -1.0,
+            color: Vec3::ZERO.into(),
+            size: Vec2::ZERO.into(),
+        }
+    }
+}
+
+// The lights of every light entity in the order they're written to the lights buffer, at most MAX_LIGHTS
+pub fn gpu_lights(world: &World) -> Vec<GpuLight> {
+    let grid = world.voxel_grid.as_ref().unwrap();
+
+    world
+        .get_components::<Light>()
+        .iter()
+        .take(MAX_LIGHTS)
+        .map(|light| GpuLight::new(light, grid))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, ConfigValue};
+
+    #[test]
+    fn configured_light_reaches_the_lights_buffer() {
+        let mut config = Config::new();
+        config.set_var("game_light_kind", ConfigValue::String("spot".to_string()));
+        config.set_var("game_light_position_x", ConfigValue::F32(10.0));
+        config.set_var("game_light_color_g", ConfigValue::F32(0.5));
+        config.set_var("game_light_intensity", ConfigValue::F32(8.0));
+        config.set_var("game_light_angle", ConfigValue::F32(60.0));
+
+        let mut grid = VoxelGrid::new();
+        grid.voxel_size = 2.0;
+
+        let mut world = World::default();
+        world.voxel_grid = Some(grid);
+        assert!(gpu_lights(&world).is_empty());
+
+        let handle = world.create_entity();
+        world.add_component(handle, Box::new(Light::from_config(&config).unwrap()));
+
+        let lights = gpu_lights(&world);
+        assert_eq!(lights.len(), 1);
+
+        // Positions are in voxels and the intensity falls off over voxels instead of world units
+        let light = lights[0];
+        assert_eq!(light.kind, 1);
+        assert_eq!(Vec3::from(light.position), Vec3::new(5.0, 0.0, 32.0));
+        assert_eq!(Vec3::from(light.color), Vec3::new(2.0, 1.0, 2.0));
+        assert!((light.cos_angle - 0.5).abs() < 1e-6);
+    }
+}
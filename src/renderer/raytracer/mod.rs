@@ -2,7 +2,10 @@ use std::{cell::RefCell, rc::Rc, sync::Arc};
 
 use crate::game::World;
 
-use self::uniforms::Uniforms;
+use self::{
+    lights::{gpu_lights, GpuLight, MAX_LIGHTS},
+    uniforms::Uniforms,
+};
 use futures::lock::Mutex;
 use wgpu::{util::DeviceExt, Buffer, CommandEncoder, Texture, TextureView};
 
@@ -10,8 +13,27 @@ use crevice::std430::{AsStd430, Std430};
 
 use super::{glsl_loader, texture_atlas::TextureAtlas, RenderContext, Vertex, VERTICES};
 
+mod lights;
 mod uniforms;
 
+// The values renderer_debug_view can take, in the order of the DEBUG_VIEW defines in raytrace.frag
+pub const DEBUG_VIEWS: [&str; 8] = [
+    "final",
+    "albedo",
+    "normals",
+    "depth",
+    "step_heatmap",
+    "octree_heatmap",
+    "primary_ray_only",
+    "denoiser_input",
+];
+
+// The values renderer_raytracer_gi_mode can take, in the order of the GI_MODE defines in raytrace.frag
+pub const GI_MODES: [&str; 2] = ["path_tracing", "cone_tracing"];
+
+// The values renderer_sky_model can take, in the order of the SKY_MODEL defines in raytrace.frag
+pub const SKY_MODELS: [&str; 3] = ["color", "environment_map", "preetham"];
+
 pub struct Raytracer {
     render_pipeline: wgpu::RenderPipeline,
     raytrace_uniforms: Uniforms,
@@ -40,6 +62,47 @@ impl Raytracer {
 
         let (shader_vertex, shader_fragment) = shaders;
 
+        // The environment map is only registered when it loads, the uniforms fall back to the sky color without it
+        let environment_path = world
+            .lock()
+            .await
+            .config
+            .as_ref()
+            .unwrap()
+            .get_var("renderer_environment_map_path")
+            .unwrap()
+            .as_string();
+
+        if !environment_path.is_empty() {
+            if let Err(why) = std::fs::read(&environment_path)
+                .map_err(image::ImageError::from)
+                .and_then(|bytes| {
+                    atlas.borrow_mut().register_from_hdr_image(
+                        "raytracer_binding_environment",
+                        &bytes,
+                        context,
+                    )
+                })
+            {
+                eprintln!(
+                    "unable to load environment map {}, using the sky color instead: {}",
+                    environment_path, why
+                );
+            }
+        }
+
+        atlas.borrow_mut().register_buffer(
+            "raytracer_lights",
+            wgpu::BufferDescriptor {
+                label: Some("Raytracer Lights"),
+                size: (MAX_LIGHTS * GpuLight::empty().as_std430().as_bytes().len())
+                    as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            },
+            context,
+        );
+
         let raytrace_uniforms = Uniforms::new(context, world.clone(), atlas.clone()).await;
 
         let raytrace_uniform_buffer =
@@ -86,6 +149,33 @@ impl Raytracer {
             .get_view("voxelizer_attachment_world", context)
             .unwrap();
 
+        let dynamic_texture_view = atlas
+            .borrow_mut()
+            .get_view("voxelizer_attachment_dynamic", context)
+            .unwrap();
+
+        let environment_texture_view = atlas
+            .borrow()
+            .get_view("raytracer_binding_environment", context)
+            .unwrap_or_else(|| {
+                context
+                    .device
+                    .create_texture(&wgpu::TextureDescriptor {
+                        size: wgpu::Extent3d {
+                            width: 1,
+                            height: 1,
+                            depth_or_array_layers: 1,
+                        },
+                        mip_level_count: 1,
+                        sample_count: 1,
+                        dimension: wgpu::TextureDimension::D2,
+                        format: wgpu::TextureFormat::Rgba32Float,
+                        usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                        label: Some("Unused Environment Map"),
+                    })
+                    .create_view(&wgpu::TextureViewDescriptor::default())
+            });
+
         // let world_sampler = context.device.create_sampler(&wgpu::SamplerDescriptor {
         //     address_mode_u: wgpu::AddressMode::ClampToEdge,
         //     address_mode_v: wgpu::AddressMode::ClampToEdge,
@@ -121,6 +211,86 @@ impl Raytracer {
                             },
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 3,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 4,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 5,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 6,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D2,
+                                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 7,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 8,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            binding: 9,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            count: None,
+                        },
                     ],
                     label: Some("texture_bind_group_layout"),
                 });
@@ -215,6 +385,62 @@ impl Raytracer {
                                 .unwrap(),
                         ),
                     },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas
+                                .borrow()
+                                .get_view("world_streamer_indirection", context)
+                                .unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: atlas
+                            .borrow()
+                            .get_buffer("world_streamer_uniforms", context)
+                            .unwrap()
+                            .as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::TextureView(&dynamic_texture_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 5,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas.borrow().get_view("distance_field", context).unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 6,
+                        resource: wgpu::BindingResource::TextureView(&environment_texture_view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 7,
+                        resource: atlas
+                            .borrow()
+                            .get_buffer("world_streamer_materials", context)
+                            .unwrap()
+                            .as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 8,
+                        resource: atlas
+                            .borrow()
+                            .get_buffer("raytracer_lights", context)
+                            .unwrap()
+                            .as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 9,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas
+                                .borrow()
+                                .get_view("world_streamer_material_ids", context)
+                                .unwrap(),
+                        ),
+                    },
                 ],
                 label: Some("world_bind_group"),
             });
@@ -304,6 +530,22 @@ impl Raytracer {
             0,
             bytemuck::cast_slice(self.raytrace_uniforms.as_std430().as_bytes()),
         );
+
+        let lights = gpu_lights(&*self.world.lock().await)
+            .iter()
+            .flat_map(|light| light.as_std430().as_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        if !lights.is_empty() {
+            context.queue.write_buffer(
+                self.atlas
+                    .borrow()
+                    .get_buffer("raytracer_lights", context)
+                    .unwrap(),
+                0,
+                &lights,
+            );
+        }
     }
 
     fn uniform_bind_group(&self) -> &wgpu::BindGroup {
@@ -317,3 +559,220 @@ impl Raytracer {
     // TOOD; move into resize trait
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>, context: &RenderContext) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use glam::{BVec3, DVec3, IVec3, Vec3, Vec3Swizzles};
+
+    // A cube of voxels with an occupancy mip chain, a cell of a level is filled when any of the eight below it is
+    struct Octree {
+        size: i32,
+        levels: Vec<Vec<bool>>,
+    }
+
+    impl Octree {
+        fn new(size: i32, levels: usize, filled: impl Fn(IVec3) -> bool) -> Self {
+            let mut octree = Self {
+                size,
+                levels: vec![Vec::new(); levels],
+            };
+
+            for level in 0..levels {
+                let level_size = size >> level;
+                octree.levels[level] = (0..level_size.pow(3))
+                    .map(|index| {
+                        let c = IVec3::new(
+                            index % level_size,
+                            index / level_size % level_size,
+                            index / (level_size * level_size),
+                        );
+
+                        if level == 0 {
+                            filled(c)
+                        } else {
+                            (0..8).any(|child| {
+                                let offset = IVec3::new(child & 1, child >> 1 & 1, child >> 2);
+                                octree.get(c * 2 + offset, level - 1)
+                            })
+                        }
+                    })
+                    .collect();
+            }
+
+            octree
+        }
+
+        // Mirrors getVoxel, everything outside is empty
+        fn get(&self, c: IVec3, level: usize) -> bool {
+            let level_size = self.size >> level;
+
+            c.min_element() >= 0
+                && c.max_element() < level_size
+                && self.levels[level][(c.x + (c.y + c.z * level_size) * level_size) as usize]
+        }
+    }
+
+    fn as_vec3(b: BVec3) -> Vec3 {
+        Vec3::select(b, Vec3::ONE, Vec3::ZERO)
+    }
+
+    // Mirrors the octree traversal of trace() in raytrace.frag, returns the first filled voxel, or the first empty one
+    // for a ray inside of a transmissive voxel
+    fn trace(
+        octree: &Octree,
+        raypos: Vec3,
+        raydir: Vec3,
+        max_level: i32,
+        inside: bool,
+    ) -> Option<IVec3> {
+        let (world_min, world_max) = (Vec3::ZERO, Vec3::splat(octree.size as f32));
+
+        let mut level = 0;
+        let mut grid_position = raypos.floor().as_ivec3();
+
+        let delta_dist = (Vec3::ONE / raydir).abs();
+        let raydir_sign = raydir.cmpgt(Vec3::ZERO);
+
+        let cell = |grid_position: IVec3, level: i32| grid_position & IVec3::splat(-1 << level);
+        let side_dist = |grid_position: IVec3, level: i32| {
+            let next_edge =
+                cell(grid_position, level).as_vec3() + as_vec3(raydir_sign) * (1 << level) as f32;
+            ((next_edge - raypos) * delta_dist).abs()
+        };
+
+        let mut side = side_dist(grid_position, level);
+        let mut dist = 0.0;
+
+        for _ in 0..10000 {
+            let position = grid_position.as_vec3();
+
+            if !(position.cmpgt(world_min - 2.0).all() && position.cmplt(world_max + 1.0).all()) {
+                return None;
+            }
+
+            let non_empty = octree.get(grid_position >> level, level as usize);
+
+            if non_empty != inside && level == 0 {
+                return Some(grid_position);
+            } else if non_empty && level > 0 {
+                let cell_min = cell(grid_position, level);
+                level -= 1;
+                grid_position = (raypos + raydir * dist)
+                    .floor()
+                    .as_ivec3()
+                    .clamp(cell_min, cell_min + IVec3::splat((2 << level) - 1));
+            } else if level < max_level
+                && !inside
+                && !octree.get(grid_position >> (level + 1), level as usize + 1)
+            {
+                level += 1;
+            } else {
+                dist = side.min_element();
+
+                let mask = side.cmple(side.yzx().min(side.zxy()));
+                let vstep = IVec3::select(raydir_sign, IVec3::splat(1 << level), IVec3::splat(-1));
+                grid_position = cell(grid_position, level)
+                    + IVec3::select(mask, IVec3::ONE, IVec3::ZERO) * vstep;
+                side += as_vec3(mask) * delta_dist * (1 << level) as f32;
+                continue;
+            }
+
+            side = side_dist(grid_position, level);
+        }
+
+        None
+    }
+
+    // Steps through every voxel the ray passes in double precision
+    fn brute_force(octree: &Octree, raypos: Vec3, raydir: Vec3, inside: bool) -> Option<IVec3> {
+        let (raypos, raydir) = (raypos.as_dvec3(), raydir.as_dvec3());
+        let mut c = raypos.floor().as_ivec3();
+        let delta = (DVec3::ONE / raydir).abs();
+        let next_edge =
+            c.as_dvec3() + DVec3::select(raydir.cmpgt(DVec3::ZERO), DVec3::ONE, DVec3::ZERO);
+        let mut side = ((next_edge - raypos) / raydir).abs();
+
+        loop {
+            if c.min_element() < -1 || c.max_element() > octree.size {
+                return None;
+            }
+
+            if octree.get(c, 0) != inside {
+                return Some(c);
+            }
+
+            let axis = if side.x <= side.y && side.x <= side.z {
+                0
+            } else if side.y <= side.z {
+                1
+            } else {
+                2
+            };
+
+            c[axis] += if raydir[axis] > 0.0 { 1 } else { -1 };
+            side[axis] += delta[axis];
+        }
+    }
+
+    #[test]
+    fn octree_traversal_matches_brute_force() {
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32
+        };
+
+        // Clusters of voxels with plenty of empty space between them so the traversal moves up and down the levels
+        let centers = (0..12)
+            .map(|_| Vec3::new(random(), random(), random()) * 64.0)
+            .collect::<Vec<_>>();
+        let octree = Octree::new(64, 4, |c| {
+            let p = c.as_vec3() + 0.5;
+            centers.iter().any(|center| p.distance(*center) < 5.0)
+                && (c.x * 7 + c.y * 13 + c.z * 3) % 5 != 0
+        });
+
+        // A glass slab several voxels thick, rays that refracted into it walk filled voxels until they leave it
+        let slab = Octree::new(64, 4, |c| (20..24).contains(&c.z));
+
+        let check = |octree: &Octree, raypos: Vec3, raydir: Vec3, inside: bool| {
+            let expected = brute_force(octree, raypos, raydir, inside);
+
+            for max_level in 0..4 {
+                assert_eq!(
+                    trace(octree, raypos, raydir, max_level, inside),
+                    expected,
+                    "ray from {} along {} with {} levels{}",
+                    raypos,
+                    raydir,
+                    max_level + 1,
+                    if inside { " inside" } else { "" }
+                );
+            }
+
+            expected
+        };
+
+        let mut hits = 0;
+
+        for _ in 0..2000 {
+            let raypos = Vec3::new(random(), random(), random()) * 64.0;
+            let raydir = (Vec3::new(random(), random(), random()) * 2.0 - 1.0).normalize();
+            hits += check(&octree, raypos, raydir, false).is_some() as usize;
+        }
+
+        assert!(hits > 100);
+
+        for _ in 0..500 {
+            let raypos = Vec3::new(random() * 64.0, random() * 64.0, 20.0 + random() * 4.0);
+            let raydir = (Vec3::new(random(), random(), random()) * 2.0 - 1.0).normalize();
+            assert!(check(&slab, raypos, raydir, true).is_some());
+
+            let raypos = Vec3::new(random(), random(), random()) * 64.0;
+            let raydir = (Vec3::new(random(), random(), random()) * 2.0 - 1.0).normalize();
+            check(&slab, raypos, raydir, false);
+        }
+    }
+}
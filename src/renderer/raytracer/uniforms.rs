@@ -2,24 +2,60 @@ use crevice::std430::AsStd430;
 use std::{cell::RefCell, convert::TryInto, rc::Rc, sync::Arc};
 
 use futures::lock::Mutex;
-use glam::{IVec2, IVec3, Mat4, Vec3};
+use glam::{IVec2, IVec3, Mat3, Mat4, Vec3};
 
 use crate::{
-    game::World,
-    renderer::{texture_atlas::TextureAtlas, RenderContext},
+    game::{entity::components::Light, World},
+    renderer::{distance_field::BRICK_LEVEL, texture_atlas::TextureAtlas, RenderContext},
 };
 
+use super::{lights::MAX_LIGHTS, DEBUG_VIEWS, GI_MODES, SKY_MODELS};
+
 #[derive(Copy, Clone, Debug, AsStd430)]
 pub struct Uniforms {
     camera_matrix: mint::ColumnMatrix4<f32>,
-    scene_size: mint::Vector3<i32>,
     resolution: mint::Vector2<i32>,
     samples: i32,
     primary_ray_only: i32,
     frame_count: i32,
     max_steps: i32,
+    max_bounces: i32,
+    roulette_depth: i32, // Paths past this many bounces are ended by russian roulette
     octree_depth: i32,
     focal_length: f32,
+    acceleration: i32, // 0 steps through the mip chain, 1 jumps through the distance field
+    brick_level: i32,
+    debug_view: i32,  // The index of the view in DEBUG_VIEWS
+    gi_mode: i32,     // The index of the mode in GI_MODES
+    light_count: i32, // How many entries of the lights buffer are in use
+    lighting: Lighting,
+    fog: Fog,
+}
+
+// Kept apart from Uniforms because the layout code crevice derives grows exponentially with the fields of one struct
+#[derive(Copy, Clone, Debug, AsStd430)]
+pub struct Lighting {
+    sun_direction: mint::Vector3<f32>,
+    sun_sharpness: f32,
+    sun_color: mint::Vector3<f32>,
+    sun_power: f32,
+    sky_color: mint::Vector3<f32>,
+    sky_power: f32,
+    sky_model: i32, // The index of the model in SKY_MODELS
+    sky_turbidity: f32,
+    sky_exposure: f32,
+    environment_intensity: f32,
+    environment_rotation: f32, // In radians around the up axis
+}
+
+// Height fog in voxel space, kept apart from Uniforms for the same reason as Lighting
+#[derive(Copy, Clone, Debug, AsStd430)]
+pub struct Fog {
+    albedo: mint::Vector3<f32>,
+    density: f32, // The extinction per voxel at the fog height
+    height: f32,
+    height_falloff: f32, // Per voxel, zero makes the fog homogeneous
+    anisotropy: f32,
 }
 
 impl Uniforms {
@@ -29,15 +65,41 @@ impl Uniforms {
         atlas: Rc<RefCell<TextureAtlas>>,
     ) -> Self {
         let mut uniforms = Self {
-            scene_size: mint::Vector3 { x: 0, y: 0, z: 0 },
             resolution: mint::Vector2 { x: 0, y: 0 },
             samples: 0,
             frame_count: 0,
             max_steps: 0,
+            max_bounces: 0,
+            roulette_depth: 0,
             octree_depth: 0,
             focal_length: 0.0,
             primary_ray_only: 0,
             camera_matrix: Mat4::IDENTITY.into(),
+            acceleration: 0,
+            brick_level: BRICK_LEVEL as i32,
+            debug_view: 0,
+            gi_mode: 0,
+            light_count: 0,
+            lighting: Lighting {
+                sun_direction: Vec3::Z.into(),
+                sun_sharpness: 0.0,
+                sun_color: Vec3::ZERO.into(),
+                sun_power: 0.0,
+                sky_color: Vec3::ZERO.into(),
+                sky_power: 0.0,
+                sky_model: 0,
+                sky_turbidity: 0.0,
+                sky_exposure: 0.0,
+                environment_intensity: 0.0,
+                environment_rotation: 0.0,
+            },
+            fog: Fog {
+                albedo: Vec3::ZERO.into(),
+                density: 0.0,
+                height: 0.0,
+                height_falloff: 0.0,
+                anisotropy: 0.0,
+            },
         };
         uniforms.update(context, world, atlas).await;
         uniforms
@@ -67,20 +129,133 @@ impl Uniforms {
         self.frame_count = player.camera.frame_count as i32;
         self.focal_length = player.camera.focal_length();
         let info = atlas.borrow_mut().get_info("voxelizer_attachment_world", context).unwrap();
-        self.scene_size = IVec3::new(info.size.0 as i32, info.size.1 as i32, info.size.2 as i32).into();
-        self.octree_depth = info.mip_levels as i32;
+        self.octree_depth = config
+            .get_var("renderer_raytracer_octree_depth")
+            .unwrap()
+            .as_i32()
+            .clamp(1, info.mip_levels as i32);
         self.max_steps = config
             .get_var("renderer_raytracer_max_steps")
             .unwrap()
             .as_i32(); // TODO; config refactor
+        self.max_bounces = config
+            .get_var("renderer_raytracer_max_bounces")
+            .unwrap()
+            .as_i32()
+            .max(0);
+        self.roulette_depth = config
+            .get_var("renderer_raytracer_roulette_depth")
+            .unwrap()
+            .as_i32()
+            .max(0);
         self.samples = config
             .get_var("renderer_raytracer_samples")
             .unwrap()
             .as_i32(); // TODO; config refactor
-        self.camera_matrix = player.transform.as_matrix().into();
+        self.camera_matrix = (world.voxel_grid.as_ref().unwrap().world_to_voxel()
+            * player.transform.as_matrix())
+        .into();
         self.primary_ray_only = config
             .get_var("renderer_raytracer_do_lighting")
             .unwrap()
             .as_i32();
+        self.acceleration = match config
+            .get_var("renderer_raytracer_acceleration")
+            .unwrap()
+            .as_string()
+            .as_str()
+        {
+            "distance_field" => 1,
+            _ => 0,
+        };
+        let debug_view = config.get_var("renderer_debug_view").unwrap().as_string();
+        self.debug_view = DEBUG_VIEWS
+            .iter()
+            .position(|view| *view == debug_view)
+            .unwrap_or(0) as i32;
+        let gi_mode = config
+            .get_var("renderer_raytracer_gi_mode")
+            .unwrap()
+            .as_string();
+        self.gi_mode = GI_MODES
+            .iter()
+            .position(|mode| *mode == gi_mode)
+            .unwrap_or(0) as i32;
+        self.light_count = world.get_components::<Light>().len().min(MAX_LIGHTS) as i32;
+
+        let config_vec3 = |name: &str, x: &str, y: &str, z: &str| {
+            Vec3::new(
+                config.get_var(&format!("{}_{}", name, x)).unwrap().as_f32(),
+                config.get_var(&format!("{}_{}", name, y)).unwrap().as_f32(),
+                config.get_var(&format!("{}_{}", name, z)).unwrap().as_f32(),
+            )
+        };
+
+        let mut sun_direction = config_vec3("renderer_sun_direction", "x", "y", "z");
+
+        if config
+            .get_var("renderer_sun_time_of_day")
+            .unwrap()
+            .as_bool()
+        {
+            // The sun turns once a day around the y axis, rising in +x and standing at the configured direction at noon
+            let hours = config.get_var("game_world_time_of_day").unwrap().as_f32();
+            sun_direction = Mat3::from_rotation_y((12.0 - hours) / 24.0 * std::f32::consts::TAU)
+                * sun_direction;
+        }
+
+        self.lighting.sun_direction = sun_direction.normalize_or_zero().into();
+        self.lighting.sun_sharpness = config.get_var("renderer_sun_sharpness").unwrap().as_f32();
+        self.lighting.sun_color = config_vec3("renderer_sun_color", "r", "g", "b").into();
+        self.lighting.sun_power = config.get_var("renderer_sun_power").unwrap().as_f32();
+        self.lighting.sky_color = config_vec3("renderer_sky_color", "r", "g", "b").into();
+        self.lighting.sky_power = config.get_var("renderer_sky_power").unwrap().as_f32();
+
+        // Without a loaded environment map the flat sky color is used
+        let sky_model = config.get_var("renderer_sky_model").unwrap().as_string();
+        let environment_loaded = atlas
+            .borrow()
+            .get_info("raytracer_binding_environment", context)
+            .is_some();
+        self.lighting.sky_model = SKY_MODELS
+            .iter()
+            .position(|model| {
+                *model == sky_model && (environment_loaded || *model != "environment_map")
+            })
+            .unwrap_or(0) as i32;
+        self.lighting.sky_turbidity = config.get_var("renderer_sky_turbidity").unwrap().as_f32();
+        self.lighting.sky_exposure = config.get_var("renderer_sky_exposure").unwrap().as_f32();
+        self.lighting.environment_intensity = config
+            .get_var("renderer_environment_map_intensity")
+            .unwrap()
+            .as_f32();
+        self.lighting.environment_rotation = config
+            .get_var("renderer_environment_map_rotation")
+            .unwrap()
+            .as_f32()
+            .to_radians();
+
+        // The fog is configured in world units
+        let grid = world.voxel_grid.as_ref().unwrap();
+        self.fog.albedo = config_vec3("renderer_fog_albedo", "r", "g", "b").into();
+        self.fog.density =
+            config.get_var("renderer_fog_density").unwrap().as_f32() * grid.voxel_size;
+        self.fog.height = grid
+            .voxel_position(Vec3::new(
+                0.0,
+                0.0,
+                config.get_var("renderer_fog_height").unwrap().as_f32(),
+            ))
+            .z;
+        self.fog.height_falloff = config
+            .get_var("renderer_fog_height_falloff")
+            .unwrap()
+            .as_f32()
+            * grid.voxel_size;
+        self.fog.anisotropy = config
+            .get_var("renderer_fog_anisotropy")
+            .unwrap()
+            .as_f32()
+            .clamp(-0.99, 0.99);
     }
 }
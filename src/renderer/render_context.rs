@@ -10,6 +10,7 @@ pub struct RenderContext {
     pub frame_count: u64,
     pub frame_time: f32,
     pub render_time: f32,
+    pub mipmap_time: Option<f32>,
 }
 
 impl RenderContext {
@@ -37,7 +38,9 @@ impl RenderContext {
                 &wgpu::DeviceDescriptor {
                     features: wgpu::Features::SPIRV_SHADER_PASSTHROUGH
                         | wgpu::Features::CLEAR_COMMANDS
-                        | wgpu::Features::POLYGON_MODE_LINE,
+                        | wgpu::Features::POLYGON_MODE_LINE
+                        | (adapter.features() & wgpu::Features::CONSERVATIVE_RASTERIZATION) // Optional, used by the voxelizer
+                        | (adapter.features() & wgpu::Features::TIMESTAMP_QUERY), // Optional, used to time the mipmapper
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -55,6 +58,7 @@ impl RenderContext {
             frame_count: 0,
             frame_time: 0.0,
             render_time: 0.0,
+            mipmap_time: None,
         };
 
         context
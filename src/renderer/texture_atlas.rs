@@ -112,6 +112,81 @@ impl TextureAtlas {
         );
     }
 
+    // Loads a Radiance .hdr image as a linear Rgba32Float texture, alpha is one everywhere
+    pub fn register_from_hdr_image<S>(
+        &mut self,
+        name: S,
+        bytes: &[u8],
+        context: &RenderContext,
+    ) -> image::ImageResult<()>
+    where
+        S: Into<String>,
+    {
+        let name: String = name.into();
+
+        let decoder = image::codecs::hdr::HdrDecoder::new(bytes)?;
+        let metadata = decoder.metadata();
+
+        // Creating a texture larger than the device allows would panic, so it's refused like an unsupported image
+        let max_dimension = context.device.limits().max_texture_dimension_2d;
+
+        if metadata.width > max_dimension || metadata.height > max_dimension {
+            return Err(image::ImageError::Limits(
+                image::error::LimitError::from_kind(image::error::LimitErrorKind::DimensionError),
+            ));
+        }
+        let texels = decoder
+            .read_image_hdr()?
+            .iter()
+            .flat_map(|texel| [texel[0], texel[1], texel[2], 1.0])
+            .collect::<Vec<f32>>();
+
+        let texture_size = wgpu::Extent3d {
+            width: metadata.width,
+            height: metadata.height,
+            depth_or_array_layers: 1,
+        };
+
+        let texture = context.device.create_texture(&wgpu::TextureDescriptor {
+            size: texture_size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some(name.as_str()),
+        });
+
+        context.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&texels),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(16 * metadata.width),
+                rows_per_image: std::num::NonZeroU32::new(metadata.height),
+            },
+            texture_size,
+        );
+
+        self.textures.insert(
+            name,
+            TextureType::DescriptorTexture(
+                texture,
+                TextureInfo {
+                    size: (metadata.width, metadata.height, 1),
+                    mip_levels: 1,
+                },
+            ),
+        );
+
+        Ok(())
+    }
+
     pub fn register_swapchain<S>(&mut self, name: S, context: &RenderContext)
     where
         S: Into<String>,
@@ -2,7 +2,7 @@ use std::{cell::RefCell, convert::TryInto, mem::size_of, num::NonZeroU32, rc::Rc
 
 use crevice::std430::{AsStd430, Std430};
 use futures::lock::Mutex;
-use glam::IVec2;
+use glam::{BVec3, IVec2, IVec3, Mat4, UVec3, Vec3};
 use wgpu::util::DeviceExt;
 use winit::dpi::PhysicalSize;
 
@@ -10,25 +10,26 @@ use crate::{
     game::{
         entity::components::model::{ModelVertex, Vertex},
         entity::components::Model,
-        World,
+        world::CHUNK_SIZE,
+        Transform, World,
     },
     renderer::{glsl_loader, RenderContext},
 };
 
 use self::uniforms::Uniforms;
 
-use super::texture_atlas::TextureAtlas;
+use super::{clear::Clear, texture_atlas::TextureAtlas, world_streamer::WorldStreamer};
 
 mod uniforms;
 
+// Models are voxelized into the dynamic layer of the chunk pool when they move. The region they occupied before
+// is cleared and every model overlapping a changed region is voxelized again.
 pub struct Voxelizer {
     render_pipeline: wgpu::RenderPipeline,
     uniforms: Uniforms,
-    uniforms_buffer: wgpu::Buffer,
-    uniform_bind_group: wgpu::BindGroup,
+    uniform_bind_group_layout: wgpu::BindGroupLayout,
     image_write_bind_group: wgpu::BindGroup,
-    depth_texture: wgpu::Texture,
-    depth_texture_view: wgpu::TextureView,
+    clear: Clear,
     render_texture: wgpu::Texture,
     render_texture_view: wgpu::TextureView,
     size: PhysicalSize<u32>,
@@ -53,46 +54,16 @@ impl Voxelizer {
 
         let (shader_vertex, shader_fragment) = shaders;
 
-        let texture_size = wgpu::Extent3d {
-            width: 128,
-            height: 128,
-            depth_or_array_layers: 128,
-        };
-
-        atlas.borrow_mut().register_from_descriptor(
-            "voxelizer_attachment_world",
-            wgpu::TextureDescriptor {
-                size: texture_size,
-                mip_level_count: ((((texture_size.width)
-                    .min((texture_size.height).min(texture_size.depth_or_array_layers))
-                    as f32)
-                    .log2())
-                .floor()
-                    - 0.0)
-                    .max(0.0) as u32
-                    - 1,
-                label: Some("scene_texture"),
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D3,
-                format: wgpu::TextureFormat::Rgba32Float,
-                usage: wgpu::TextureUsages::TEXTURE_BINDING
-                    | wgpu::TextureUsages::COPY_DST
-                    | wgpu::TextureUsages::STORAGE_BINDING,
-            },
-            context,
-        );
+        // Voxels are written to the chunks of the window streamed in around the player
+        let window_size = atlas
+            .borrow()
+            .get_info("world_streamer_indirection", context)
+            .unwrap()
+            .size;
+        let window_size = UVec3::new(window_size.0, window_size.1, window_size.2) * CHUNK_SIZE;
 
         let uniforms = Uniforms::new(context, world.clone(), atlas.clone()).await;
 
-        let uniforms_buffer =
-            context
-                .device
-                .create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                    label: Some("Voxelizing Uniforms"),
-                    contents: bytemuck::cast_slice(uniforms.as_std430().as_bytes()),
-                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-                });
-
         let material_layout =
             context
                 .device
@@ -135,17 +106,6 @@ impl Voxelizer {
                     label: Some("Voxelizer Uniform Bind Group Layout"),
                 });
 
-        let uniform_bind_group = context
-            .device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                layout: &uniform_bind_group_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: uniforms_buffer.as_entire_binding(),
-                }],
-                label: Some("Voxelizer Uniform Bind Group"),
-            });
-
         let image_write_bind_group_layout =
             context
                 .device
@@ -162,6 +122,26 @@ impl Voxelizer {
                             visibility: wgpu::ShaderStages::FRAGMENT,
                             count: None,
                         },
+                        wgpu::BindGroupLayoutEntry {
+                            ty: wgpu::BindingType::Texture {
+                                multisampled: false,
+                                view_dimension: wgpu::TextureViewDimension::D3,
+                                sample_type: wgpu::TextureSampleType::Uint,
+                            },
+                            binding: 1,
+                            visibility: wgpu::ShaderStages::FRAGMENT,
+                            count: None,
+                        },
+                        wgpu::BindGroupLayoutEntry {
+                            ty: wgpu::BindingType::Buffer {
+                                ty: wgpu::BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            binding: 2,
+                            visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                            count: None,
+                        },
                     ],
                 });
 
@@ -176,7 +156,7 @@ impl Voxelizer {
                             &atlas
                                 .borrow()
                                 .get_view_descriptor(
-                                    "voxelizer_attachment_world",
+                                    "voxelizer_attachment_dynamic",
                                     &wgpu::TextureViewDescriptor {
                                         label: Some("mip"),
                                         format: None,
@@ -191,7 +171,24 @@ impl Voxelizer {
                                 )
                                 .unwrap(),
                         ),
-                    }
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(
+                            &atlas
+                                .borrow()
+                                .get_view("world_streamer_indirection", context)
+                                .unwrap(),
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: atlas
+                            .borrow()
+                            .get_buffer("world_streamer_uniforms", context)
+                            .unwrap()
+                            .as_entire_binding(),
+                    },
                 ],
                 label: Some("Voxelizer Image Write Bind Group"),
             });
@@ -236,15 +233,12 @@ impl Voxelizer {
                         cull_mode: None,
                         polygon_mode: wgpu::PolygonMode::Fill,
                         unclipped_depth: false,
-                        conservative: false,
+                        conservative: context
+                            .device
+                            .features()
+                            .contains(wgpu::Features::CONSERVATIVE_RASTERIZATION),
                     },
-                    depth_stencil: Some(wgpu::DepthStencilState {
-                        format: wgpu::TextureFormat::Depth24Plus,
-                        depth_write_enabled: true,
-                        depth_compare: wgpu::CompareFunction::Less,
-                        stencil: wgpu::StencilState::default(),
-                        bias: wgpu::DepthBiasState::default(),
-                    }),
+                    depth_stencil: None, // Hidden surfaces have to be voxelized as well
                     multisample: wgpu::MultisampleState {
                         count: 1,
                         mask: !0,
@@ -253,24 +247,15 @@ impl Voxelizer {
                     multiview: None,
                 });
 
+        // The model is projected along each axis into the same target, which fits the largest dimension of the window
+        let target_size = window_size.max_element();
+
         let extent = wgpu::Extent3d {
-            width: 128, //TODO fix hardcoded scene size
-            height: 128,
+            width: target_size,
+            height: target_size,
             depth_or_array_layers: 1,
         };
 
-        let depth_texture = context.device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("Voxelizer Depth Texture"),
-            size: extent,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Depth24Plus,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        });
-
-        let depth_texture_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
-
         let render_texture = context.device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Voxelizer Render Texture"),
             size: extent,
@@ -284,13 +269,13 @@ impl Voxelizer {
         let render_texture_view =
             render_texture.create_view(&wgpu::TextureViewDescriptor::default());
 
+        let clear = Clear::new(context, atlas.clone()).await;
+
         Self {
             render_pipeline,
             uniforms,
-            uniforms_buffer,
-            uniform_bind_group,
-            depth_texture,
-            depth_texture_view,
+            uniform_bind_group_layout,
+            clear,
             render_texture,
             render_texture_view,
             size,
@@ -300,14 +285,106 @@ impl Voxelizer {
         }
     }
 
-    pub async fn render(&mut self, encoder: &mut wgpu::CommandEncoder, context: &RenderContext) {
+    // Voxelizes the models that changed and the models overlapping chunks that were just streamed in, returns the
+    // boxes of the dynamic layer that changed
+    pub async fn render(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        context: &RenderContext,
+        streamer: &WorldStreamer,
+        streamed_chunks: &[IVec3],
+    ) -> Vec<(UVec3, UVec3)> {
         self.uniforms
             .update(context, self.world.clone(), self.atlas.clone())
             .await;
 
-        let world = self.world.lock().await;
+        let mut world = self.world.lock().await;
+
+        let grid = world.voxel_grid.as_ref().unwrap();
+        let (world_to_voxel, voxel_size) = (grid.world_to_voxel(), grid.voxel_size);
+        let voxel_matrix = |model: &Model| {
+            world_to_voxel * Transform::y_up_to_z_up() * model.model_matrix(voxel_size)
+        };
+
+        let mut models = world.get_components_mut::<Model>();
+
+        // Chunks that were just streamed in took over the slot of another chunk, along with its voxelized models
+        let mut cleared_regions = streamed_chunks
+            .iter()
+            .map(|chunk_position| {
+                let min = *chunk_position * CHUNK_SIZE as i32;
+                (min, min + CHUNK_SIZE as i32)
+            })
+            .collect::<Vec<_>>();
+        let mut changed_regions = cleared_regions.clone();
+
+        for model in models.iter_mut() {
+            let model_matrix = voxel_matrix(model);
+
+            if model.needs_voxelization(model_matrix) {
+                let region = self.voxel_region(model_matrix, model.bounds());
+
+                if let Some(previous) = model.set_voxelized(model_matrix, region) {
+                    cleared_regions.push(previous);
+                    changed_regions.push(previous);
+                }
+
+                changed_regions.push(region);
+            }
+        }
+
+        if changed_regions.is_empty() {
+            return Vec::new();
+        }
+
+        let overlaps = |(min, max): (IVec3, IVec3)| {
+            changed_regions.iter().any(|(changed_min, changed_max)| {
+                min.cmplt(*changed_max).all() && changed_min.cmplt(max).all()
+            })
+        };
+
+        let models = models
+            .into_iter()
+            .map(|model| &*model)
+            .filter(|model| model.voxelized_region().map_or(false, overlaps))
+            .collect::<Vec<_>>();
+
+        // Every model gets its own uniforms, writing a shared buffer between draws would leave all of them with the last
+        // model matrix
+        let mut uniform_bind_groups = Vec::new();
+
+        for model in &models {
+            self.uniforms.update_model_matrix(voxel_matrix(model)).await;
+
+            let uniforms_buffer =
+                context
+                    .device
+                    .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                        label: Some("Voxelizing Uniforms"),
+                        contents: bytemuck::cast_slice(self.uniforms.as_std430().as_bytes()),
+                        usage: wgpu::BufferUsages::UNIFORM,
+                    });
+
+            uniform_bind_groups.push(context.device.create_bind_group(
+                &wgpu::BindGroupDescriptor {
+                    layout: &self.uniform_bind_group_layout,
+                    entries: &[wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: uniforms_buffer.as_entire_binding(),
+                    }],
+                    label: Some("Voxelizer Uniform Bind Group"),
+                },
+            ));
+        }
+
+        let cleared_boxes = cleared_regions
+            .iter()
+            .flat_map(|(min, max)| streamer.pool_boxes(*min, *max))
+            .collect::<Vec<_>>();
 
-        {
+        self.clear.render(encoder, context, &cleared_boxes).await;
+
+        if !models.is_empty() {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Voxelizer Render Pass"),
                 color_attachments: &[wgpu::RenderPassColorAttachment {
@@ -318,34 +395,43 @@ impl Voxelizer {
                         store: true,
                     },
                 }],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
+                depth_stencil_attachment: None,
             });
 
-            let components = world.get_components::<Model>();
-
             render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
             render_pass.set_bind_group(2, &self.image_write_bind_group, &[]);
 
-            for model in components.clone() {
-                self.uniforms
-                    .update_model_matrix(model.transform.model_matrix())
-                    .await;
-                context.queue.write_buffer(
-                    &self.uniforms_buffer,
-                    0,
-                    bytemuck::cast_slice(self.uniforms.as_std430().as_bytes()),
-                );
-
-                model.render(&mut render_pass, &self.uniform_bind_group);
+            for (model, uniform_bind_group) in models.iter().zip(&uniform_bind_groups) {
+                // One instance per projection axis
+                model.render_instanced(&mut render_pass, uniform_bind_group, 0..3);
             }
         }
+
+        changed_regions
+            .iter()
+            .flat_map(|(min, max)| streamer.pool_boxes(*min, *max))
+            .collect()
+    }
+
+    // The voxels covered by a model, given the matrix mapping it into voxel space
+    fn voxel_region(&self, model_matrix: Mat4, (min, max): (Vec3, Vec3)) -> (IVec3, IVec3) {
+        let mut region = (IVec3::splat(i32::MAX), IVec3::splat(i32::MIN));
+
+        for corner in 0..8 {
+            let position = Vec3::select(
+                BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                max,
+                min,
+            );
+            let voxel = model_matrix.transform_point3(position);
+
+            region = (
+                region.0.min(voxel.floor().as_ivec3()),
+                region.1.max(voxel.floor().as_ivec3()),
+            );
+        }
+
+        // Conservative rasterization can touch the voxels just outside of the bounds
+        (region.0 - IVec3::ONE, region.1 + IVec3::splat(2))
     }
 }
@@ -2,29 +2,34 @@ use crevice::std430::AsStd430;
 use std::{convert::TryInto, sync::Arc, cell::RefCell, rc::Rc};
 
 use futures::lock::Mutex;
-use glam::{IVec2, IVec3, Mat4, Vec3};
+use glam::{IVec2, IVec3, Mat4, UVec3, Vec3};
 
-use crate::{game::World, renderer::{RenderContext, texture_atlas::TextureAtlas}};
+use crate::{game::{world::CHUNK_SIZE, World}, renderer::{RenderContext, texture_atlas::TextureAtlas}};
 
 #[derive(Copy, Clone, Debug, AsStd430)]
 pub struct Uniforms {
-    scene_size: u32,
-    model_matrix: mint::ColumnMatrix4<f32>,
+    scene_size: mint::Vector3<u32>, // The size of the window of streamed chunks in voxels
+    model_matrix: mint::ColumnMatrix4<f32>, // Maps the model into voxel space
+    dominant_axis_only: u32, // Only set with conservative rasterization, which keeps thin triangles from leaving gaps
 }
 
 impl Uniforms {
     pub async fn new(context: &RenderContext, world: Arc<Mutex<World>>, atlas: Rc<RefCell<TextureAtlas>>) -> Self {
         let mut uniforms = Self {
-            scene_size: 0,
+            scene_size: mint::Vector3 { x: 0, y: 0, z: 0 },
             model_matrix: Mat4::IDENTITY.into(),
+            dominant_axis_only: context
+                .device
+                .features()
+                .contains(wgpu::Features::CONSERVATIVE_RASTERIZATION) as u32,
         };
         uniforms.update(context, world, atlas).await;
         uniforms
     }
 
     pub async fn update(&mut self, context: &RenderContext, world: Arc<Mutex<World>>, atlas: Rc<RefCell<TextureAtlas>>) {
-        let info = atlas.borrow_mut().get_info("voxelizer_attachment_world", context).unwrap();
-        self.scene_size = info.size.0; // TODO change fixed scene size
+        let info = atlas.borrow().get_info("world_streamer_indirection", context).unwrap();
+        self.scene_size = (UVec3::new(info.size.0, info.size.1, info.size.2) * CHUNK_SIZE).into();
     }
 
     pub async fn update_model_matrix(&mut self, model_matrix: Mat4) {
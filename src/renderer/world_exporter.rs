@@ -0,0 +1,201 @@
+use std::{cell::RefCell, num::NonZeroU32, rc::Rc, sync::Arc};
+
+use futures::lock::Mutex;
+use glam::{IVec3, UVec3};
+
+use crate::game::{
+    world::{Material, Scene, Voxel, CHUNK_SIZE},
+    World,
+};
+
+use super::{texture_atlas::TextureAtlas, world_streamer::WorldStreamer, RenderContext};
+
+// Reads the chunks resident on the gpu back and saves them as a scene file, voxelized models included
+pub struct WorldExporter {
+    world: Arc<Mutex<World>>,
+    atlas: Rc<RefCell<TextureAtlas>>,
+}
+
+impl WorldExporter {
+    pub async fn new(world: Arc<Mutex<World>>, atlas: Rc<RefCell<TextureAtlas>>) -> Self {
+        Self { world, atlas }
+    }
+
+    pub async fn export(&self, context: &RenderContext, streamer: &WorldStreamer) {
+        let path = self
+            .world
+            .lock()
+            .await
+            .config
+            .as_ref()
+            .unwrap()
+            .get_var("renderer_world_export_path")
+            .unwrap()
+            .as_string();
+
+        let scene = self.read_back(context, streamer).await;
+
+        if let Err(why) = scene.save(&path) {
+            eprintln!("unable to export world to {}: {}", path, why);
+        }
+    }
+
+    async fn read_back(&self, context: &RenderContext, streamer: &WorldStreamer) -> Scene {
+        let (width, height, length) = self
+            .atlas
+            .borrow()
+            .get_info("voxelizer_attachment_world", context)
+            .unwrap()
+            .size;
+
+        // Rows copied into a buffer have to be padded to the copy alignment
+        let bytes_per_row = |bytes_per_texel: u32| {
+            (bytes_per_texel * width + wgpu::COPY_BYTES_PER_ROW_ALIGNMENT - 1)
+                / wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+                * wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+        };
+
+        // The static layer, the dynamic layer and the material ids of the static layer follow each other in the buffer
+        let layers = [
+            ("voxelizer_attachment_world", 16),
+            ("voxelizer_attachment_dynamic", 16),
+            ("world_streamer_material_ids", 1),
+        ];
+        let layer_bytes = |bytes_per_texel: u32| bytes_per_row(bytes_per_texel) * height * length;
+        let offsets = layers
+            .iter()
+            .scan(0, |offset, (_, bytes_per_texel)| {
+                let start = *offset;
+                *offset += layer_bytes(*bytes_per_texel);
+                Some(start)
+            })
+            .collect::<Vec<_>>();
+
+        let buffer = context.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("World Export Buffer"),
+            size: layers
+                .iter()
+                .map(|(_, bytes_per_texel)| layer_bytes(*bytes_per_texel))
+                .sum::<u32>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("World Export Encoder"),
+            });
+
+        for ((name, bytes_per_texel), offset) in layers.iter().zip(&offsets) {
+            encoder.copy_texture_to_buffer(
+                wgpu::ImageCopyTexture {
+                    texture: self.atlas.borrow().get(*name, context).unwrap(),
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                wgpu::ImageCopyBuffer {
+                    buffer: &buffer,
+                    layout: wgpu::ImageDataLayout {
+                        offset: *offset as wgpu::BufferAddress,
+                        bytes_per_row: NonZeroU32::new(bytes_per_row(*bytes_per_texel)),
+                        rows_per_image: NonZeroU32::new(height),
+                    },
+                },
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: length,
+                },
+            );
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        context.device.poll(wgpu::Maintain::Wait);
+        mapping.await.unwrap();
+
+        // Scene positions can't be negative, so the exported scene starts at the lowest resident chunk
+        let chunks = streamer.resident_chunks().collect::<Vec<_>>();
+        let min = chunks
+            .iter()
+            .fold(IVec3::splat(i32::MAX), |min, (chunk, _)| min.min(*chunk));
+        let max = chunks
+            .iter()
+            .fold(IVec3::splat(i32::MIN), |max, (chunk, _)| max.max(*chunk));
+
+        let mut voxels = Vec::new();
+
+        {
+            let data = slice.get_mapped_range();
+
+            for (chunk_position, slot_origin) in &chunks {
+                let origin = ((*chunk_position - min) * CHUNK_SIZE as i32).as_uvec3();
+
+                for z in 0..CHUNK_SIZE {
+                    for y in 0..CHUNK_SIZE {
+                        let row = |layer: usize| {
+                            let (_, bytes_per_texel) = layers[layer];
+                            let start = (offsets[layer]
+                                + ((slot_origin.z + z) * height + slot_origin.y + y)
+                                    * bytes_per_row(bytes_per_texel)
+                                + slot_origin.x * bytes_per_texel)
+                                as usize;
+                            &data[start..start + (bytes_per_texel * CHUNK_SIZE) as usize]
+                        };
+                        let scene: &[[f32; 4]] = bytemuck::cast_slice(row(0));
+                        let dynamic: &[[f32; 4]] = bytemuck::cast_slice(row(1));
+                        let material_ids = row(2);
+
+                        for (x, (scene, dynamic)) in scene.iter().zip(dynamic).enumerate() {
+                            // Models cover the scene like they do when raytracing, with the default material
+                            let (texel, material) = if dynamic[3] != 0.0 {
+                                (dynamic, 0)
+                            } else {
+                                (scene, material_ids[x])
+                            };
+
+                            if texel[3] == 0.0 {
+                                continue;
+                            }
+
+                            let color = texel
+                                .map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+
+                            voxels.push(Voxel {
+                                position: origin + UVec3::new(x as u32, y, z),
+                                color: [color[0], color[1], color[2], 255],
+                                material,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        buffer.unmap();
+
+        let materials = self
+            .world
+            .lock()
+            .await
+            .voxel_grid
+            .as_ref()
+            .map_or_else(|| vec![Material::default()], |grid| grid.materials.clone());
+
+        let size = if chunks.is_empty() {
+            UVec3::ONE
+        } else {
+            ((max - min + IVec3::ONE) * CHUNK_SIZE as i32).as_uvec3()
+        };
+
+        Scene {
+            size,
+            voxels,
+            materials,
+        }
+    }
+}
@@ -0,0 +1,572 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    num::NonZeroU32,
+    rc::Rc,
+    sync::Arc,
+};
+
+use crevice::std430::AsStd430;
+use futures::lock::Mutex;
+use glam::{IVec3, UVec3};
+
+use crate::game::{
+    entity::components::Model,
+    world::{Chunk, Material, VoxelGrid, CHUNK_SIZE},
+    World,
+};
+
+use self::uniforms::{GpuMaterial, Uniforms, MAX_MATERIALS};
+
+use super::{texture_atlas::TextureAtlas, RenderContext};
+
+mod uniforms;
+
+// Uploading a chunk is a 512kb texture write, this keeps moving around from stalling a frame
+const CHUNKS_PER_FRAME: usize = 16;
+
+// Streams the chunks of the voxel grid around the player to the gpu. Chunks are stored in slots of a pool texture,
+// and an indirection texture covering a window of chunks around the player holds the slot of every resident chunk
+// plus one, zero meaning the chunk is empty or not loaded. Chunks covered by a voxelized model get a slot as well, even
+// when the voxel grid has nothing there. The alpha of a filled voxel is one so the mips hold how much of a cell is
+// filled, the material index of a static voxel is kept in a texture of its own and indexes a storage buffer.
+pub struct WorldStreamer {
+    world: Arc<Mutex<World>>,
+    atlas: Rc<RefCell<TextureAtlas>>,
+    uniforms: Uniforms,
+    window_origin: IVec3,
+    window_size: UVec3,
+    pool_size: UVec3,
+    resident: HashMap<IVec3, u32>,
+    free_slots: Vec<u32>,
+    indirection_dirty: bool,
+    uploaded: Vec<IVec3>,
+    streamed: Vec<IVec3>,
+    uploaded_materials: Vec<Material>, // The materials of the voxel grid as they are in the materials buffer
+}
+
+impl WorldStreamer {
+    pub async fn new(
+        context: &RenderContext,
+        world: Arc<Mutex<World>>,
+        atlas: Rc<RefCell<TextureAtlas>>,
+    ) -> Self {
+        let (radius, pool_slots) = {
+            let world = world.lock().await;
+            let config = world.config.as_ref().unwrap();
+
+            (
+                config
+                    .get_var("renderer_world_stream_radius")
+                    .unwrap()
+                    .as_i32()
+                    .max(1) as u32,
+                config
+                    .get_var("renderer_world_chunk_pool_size")
+                    .unwrap()
+                    .as_i32()
+                    .max(1) as u32,
+            )
+        };
+
+        let window_size = UVec3::splat(2 * radius);
+        let pool_size = UVec3::splat(pool_slots);
+        let pool_texels = pool_size * CHUNK_SIZE;
+        let uniforms = Uniforms::new(window_size, pool_size);
+
+        {
+            let mut atlas = atlas.borrow_mut();
+
+            // Mips stop at 4 voxels so a texel of every level lies inside of a single chunk
+            let pool_descriptor = |label| wgpu::TextureDescriptor {
+                size: wgpu::Extent3d {
+                    width: pool_texels.x,
+                    height: pool_texels.y,
+                    depth_or_array_layers: pool_texels.z,
+                },
+                mip_level_count: CHUNK_SIZE.trailing_zeros() - 1,
+                label: Some(label),
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D3,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING
+                    | wgpu::TextureUsages::COPY_DST
+                    | wgpu::TextureUsages::COPY_SRC
+                    | wgpu::TextureUsages::STORAGE_BINDING,
+            };
+
+            // The static layer holds the chunks of the voxel grid, models are voxelized into a dynamic layer with the
+            // same slots so moving them never erases the world behind them
+            atlas.register_from_descriptor(
+                "voxelizer_attachment_world",
+                pool_descriptor("scene_texture"),
+                context,
+            );
+            atlas.register_from_descriptor(
+                "voxelizer_attachment_dynamic",
+                pool_descriptor("dynamic_texture"),
+                context,
+            );
+
+            // Material indices can't be averaged, so only the first level exists
+            atlas.register_from_descriptor(
+                "world_streamer_material_ids",
+                wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: pool_texels.x,
+                        height: pool_texels.y,
+                        depth_or_array_layers: pool_texels.z,
+                    },
+                    mip_level_count: 1,
+                    label: Some("World Streamer Material Id Texture"),
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D3,
+                    format: wgpu::TextureFormat::R8Uint,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING
+                        | wgpu::TextureUsages::COPY_DST
+                        | wgpu::TextureUsages::COPY_SRC,
+                },
+                context,
+            );
+
+            atlas.register_from_descriptor(
+                "world_streamer_indirection",
+                wgpu::TextureDescriptor {
+                    size: wgpu::Extent3d {
+                        width: window_size.x,
+                        height: window_size.y,
+                        depth_or_array_layers: window_size.z,
+                    },
+                    mip_level_count: 1,
+                    label: Some("World Streamer Indirection Texture"),
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D3,
+                    format: wgpu::TextureFormat::R32Uint,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                },
+                context,
+            );
+
+            atlas.register_buffer(
+                "world_streamer_materials",
+                wgpu::BufferDescriptor {
+                    label: Some("World Streamer Materials"),
+                    size: (MAX_MATERIALS
+                        * GpuMaterial::from(&Material::default())
+                            .as_std430()
+                            .as_bytes()
+                            .len()) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                },
+                context,
+            );
+
+            atlas.register_buffer(
+                "world_streamer_uniforms",
+                wgpu::BufferDescriptor {
+                    label: Some("World Streamer Uniforms"),
+                    size: uniforms.as_std430().as_bytes().len() as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                },
+                context,
+            );
+        }
+
+        let slot_count = pool_size.x * pool_size.y * pool_size.z;
+
+        Self {
+            world,
+            atlas,
+            uniforms,
+            window_origin: IVec3::ZERO,
+            window_size,
+            pool_size,
+            resident: HashMap::new(),
+            free_slots: (0..slot_count).rev().collect(),
+            indirection_dirty: true,
+            uploaded: Vec::new(),
+            streamed: Vec::new(),
+            uploaded_materials: Vec::new(),
+        }
+    }
+
+    pub async fn render(&mut self, context: &RenderContext) {
+        let world = self.world.clone();
+        let mut world = world.lock().await;
+
+        let player_position = world.player.as_ref().unwrap().transform.position();
+
+        // Models are only known to cover a region once they have been voxelized, the voxelizer draws them again when
+        // the chunks they cover are streamed in
+        let model_regions = world
+            .get_components::<Model>()
+            .iter()
+            .filter_map(|model| model.voxelized_region())
+            .collect::<Vec<_>>();
+
+        let grid = match world.voxel_grid.as_mut() {
+            Some(grid) => grid,
+            None => return,
+        };
+
+        let materials = &grid.materials[..grid.materials.len().min(MAX_MATERIALS)];
+
+        // Materials can be edited in place, so compare the contents rather than the count
+        if materials != self.uploaded_materials.as_slice() {
+            self.uploaded_materials = materials.to_vec();
+
+            let materials = materials
+                .iter()
+                .flat_map(|material| GpuMaterial::from(material).as_std430().as_bytes().to_vec())
+                .collect::<Vec<u8>>();
+
+            context.queue.write_buffer(
+                self.atlas
+                    .borrow()
+                    .get_buffer("world_streamer_materials", context)
+                    .unwrap(),
+                0,
+                &materials,
+            );
+        }
+
+        let player_chunk =
+            VoxelGrid::chunk_position(grid.voxel_position(player_position).floor().as_ivec3());
+        let window_origin = player_chunk - self.window_size.as_ivec3() / 2;
+
+        if window_origin != self.window_origin {
+            self.window_origin = window_origin;
+            self.indirection_dirty = true;
+        }
+
+        let needed = needed_chunks(grid, &model_regions, self.window_origin, self.window_size);
+
+        // Changed chunks are uploaded again, chunks that became empty give up their slot unless a model covers them
+        for chunk_position in grid.take_dirty_chunks() {
+            if let Some(&slot) = self.resident.get(&chunk_position) {
+                if needed.contains(&chunk_position) {
+                    self.upload(context, chunk_position, slot, grid.chunk(chunk_position));
+                }
+            }
+        }
+
+        let unneeded = self
+            .resident
+            .keys()
+            .copied()
+            .filter(|chunk_position| !needed.contains(chunk_position))
+            .collect::<Vec<_>>();
+
+        for chunk_position in unneeded {
+            self.evict(chunk_position);
+        }
+
+        let distance = |chunk_position: IVec3| {
+            let offset = chunk_position - player_chunk;
+            offset.dot(offset)
+        };
+
+        let mut missing = needed
+            .into_iter()
+            .filter(|chunk_position| !self.resident.contains_key(chunk_position))
+            .collect::<Vec<_>>();
+
+        missing.sort_by_key(|chunk_position| distance(*chunk_position));
+
+        for chunk_position in missing.into_iter().take(CHUNKS_PER_FRAME) {
+            let slot = match self.free_slots.pop() {
+                Some(slot) => slot,
+                None => {
+                    // The pool is full, make room if a resident chunk is further away than this one
+                    let farthest = self
+                        .resident
+                        .keys()
+                        .copied()
+                        .max_by_key(|resident| distance(*resident));
+
+                    match farthest {
+                        Some(farthest) if distance(farthest) > distance(chunk_position) => {
+                            self.evict(farthest);
+                            self.free_slots.pop().unwrap()
+                        }
+                        _ => break,
+                    }
+                }
+            };
+
+            self.upload(context, chunk_position, slot, grid.chunk(chunk_position));
+            self.resident.insert(chunk_position, slot);
+            self.streamed.push(chunk_position);
+            self.indirection_dirty = true;
+        }
+
+        if self.indirection_dirty {
+            self.indirection_dirty = false;
+            self.write_indirection(context);
+        }
+    }
+
+    // The position of every resident chunk and the origin of its slot in the pool texture
+    pub fn resident_chunks(&self) -> impl Iterator<Item = (IVec3, UVec3)> + '_ {
+        self.resident
+            .iter()
+            .map(move |(chunk_position, slot)| (*chunk_position, self.slot_origin(*slot)))
+    }
+
+    // The chunk at the lowest corner of the window
+    pub fn window_origin(&self) -> IVec3 {
+        self.window_origin
+    }
+
+    // The chunks uploaded since the last call, their static layer and its mips have been replaced
+    pub fn take_uploaded_chunks(&mut self) -> Vec<IVec3> {
+        std::mem::take(&mut self.uploaded)
+    }
+
+    // The chunks that were given a slot since the last call, their dynamic layer still holds the previous chunk
+    pub fn take_streamed_chunks(&mut self) -> Vec<IVec3> {
+        std::mem::take(&mut self.streamed)
+    }
+
+    // Splits a region of voxels into the parts lying in resident chunks, as the origin and size of a box of texels in
+    // the pool texture. The maximum of the region is exclusive.
+    pub fn pool_boxes(&self, min: IVec3, max: IVec3) -> Vec<(UVec3, UVec3)> {
+        let chunk_size = CHUNK_SIZE as i32;
+        let (first, last) = (
+            VoxelGrid::chunk_position(min),
+            VoxelGrid::chunk_position(max - IVec3::ONE),
+        );
+
+        let mut boxes = Vec::new();
+
+        for z in first.z..=last.z {
+            for y in first.y..=last.y {
+                for x in first.x..=last.x {
+                    let chunk_position = IVec3::new(x, y, z);
+
+                    let slot = match self.resident.get(&chunk_position) {
+                        Some(slot) => *slot,
+                        None => continue,
+                    };
+
+                    let chunk_min = chunk_position * chunk_size;
+                    let start = min.max(chunk_min);
+                    let end = max.min(chunk_min + chunk_size);
+
+                    if start.cmplt(end).all() {
+                        boxes.push((
+                            self.slot_origin(slot) + (start - chunk_min).as_uvec3(),
+                            (end - start).as_uvec3(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        boxes
+    }
+
+    fn evict(&mut self, chunk_position: IVec3) {
+        if let Some(slot) = self.resident.remove(&chunk_position) {
+            self.free_slots.push(slot);
+            self.indirection_dirty = true;
+        }
+    }
+
+    fn slot_origin(&self, slot: u32) -> UVec3 {
+        UVec3::new(
+            slot % self.pool_size.x,
+            slot / self.pool_size.x % self.pool_size.y,
+            slot / (self.pool_size.x * self.pool_size.y),
+        ) * CHUNK_SIZE
+    }
+
+    // Writes the static layer of a slot, chunks that only hold models are uploaded empty to erase the previous chunk
+    fn upload(
+        &mut self,
+        context: &RenderContext,
+        chunk_position: IVec3,
+        slot: u32,
+        chunk: Option<&Chunk>,
+    ) {
+        let origin = self.slot_origin(slot);
+        self.uploaded.push(chunk_position);
+
+        let voxel_count = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+        let (texels, material_ids) = match chunk {
+            Some(chunk) => chunk
+                .colors()
+                .iter()
+                .zip(chunk.materials())
+                .map(|(color, material)| {
+                    let filled = color[3] != 0;
+
+                    (
+                        [
+                            color[0] as f32 / 255.0,
+                            color[1] as f32 / 255.0,
+                            color[2] as f32 / 255.0,
+                            if filled { 1.0 } else { 0.0 },
+                        ],
+                        if filled { *material } else { 0 },
+                    )
+                })
+                .unzip(),
+            None => (vec![[0.0; 4]; voxel_count], vec![0; voxel_count]),
+        };
+
+        let atlas = self.atlas.borrow();
+        let write = |name: &str, data: &[u8], bytes_per_texel: u32| {
+            context.queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: atlas.get(name, context).unwrap(),
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: origin.x,
+                        y: origin.y,
+                        z: origin.z,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(bytes_per_texel * CHUNK_SIZE),
+                    rows_per_image: NonZeroU32::new(CHUNK_SIZE),
+                },
+                wgpu::Extent3d {
+                    width: CHUNK_SIZE,
+                    height: CHUNK_SIZE,
+                    depth_or_array_layers: CHUNK_SIZE,
+                },
+            );
+        };
+
+        write(
+            "voxelizer_attachment_world",
+            bytemuck::cast_slice::<[f32; 4], u8>(&texels),
+            16,
+        );
+        write("world_streamer_material_ids", &material_ids, 1);
+    }
+
+    fn write_indirection(&mut self, context: &RenderContext) {
+        let atlas = self.atlas.borrow();
+        let size = self.window_size;
+
+        let mut slots = vec![0u32; (size.x * size.y * size.z) as usize];
+
+        for (chunk_position, slot) in &self.resident {
+            let local = (*chunk_position - self.window_origin).as_uvec3();
+            slots[(local.x + (local.y + local.z * size.y) * size.x) as usize] = slot + 1;
+        }
+
+        context.queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: atlas.get("world_streamer_indirection", context).unwrap(),
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            bytemuck::cast_slice(&slots),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: NonZeroU32::new(4 * size.x),
+                rows_per_image: NonZeroU32::new(size.y),
+            },
+            wgpu::Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: size.z,
+            },
+        );
+
+        self.uniforms.update(self.window_origin);
+        context.queue.write_buffer(
+            atlas
+                .get_buffer("world_streamer_uniforms", context)
+                .unwrap(),
+            0,
+            bytemuck::cast_slice(self.uniforms.as_std430().as_bytes()),
+        );
+    }
+}
+
+// The chunks of the window that need a slot, because they hold voxels of the grid or lie in the region of a model.
+// Model regions are in voxels with an exclusive maximum.
+fn needed_chunks(
+    grid: &VoxelGrid,
+    model_regions: &[(IVec3, IVec3)],
+    window_origin: IVec3,
+    window_size: UVec3,
+) -> HashSet<IVec3> {
+    let window_end = window_origin + window_size.as_ivec3();
+    let mut needed = HashSet::new();
+
+    for z in window_origin.z..window_end.z {
+        for y in window_origin.y..window_end.y {
+            for x in window_origin.x..window_end.x {
+                let chunk_position = IVec3::new(x, y, z);
+
+                if grid.chunk(chunk_position).is_some() {
+                    needed.insert(chunk_position);
+                }
+            }
+        }
+    }
+
+    for (min, max) in model_regions {
+        let first = VoxelGrid::chunk_position(*min).max(window_origin);
+        let last = VoxelGrid::chunk_position(*max - IVec3::ONE).min(window_end - IVec3::ONE);
+
+        for z in first.z..=last.z {
+            for y in first.y..=last.y {
+                for x in first.x..=last.x {
+                    needed.insert(IVec3::new(x, y, z));
+                }
+            }
+        }
+    }
+
+    needed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn models_over_empty_space_get_chunks() {
+        let mut grid = VoxelGrid::new();
+        grid.set(IVec3::new(5, 5, 5), [255; 4], 0);
+
+        // A model in the otherwise empty chunk (2, 0, 0) that pokes into (3, 0, 0) and one outside of the window
+        let chunk_size = CHUNK_SIZE as i32;
+        let regions = [
+            (
+                IVec3::new(2 * chunk_size + 4, 3, 3),
+                IVec3::new(3 * chunk_size + 1, 9, 9),
+            ),
+            (
+                IVec3::splat(-10 * chunk_size),
+                IVec3::splat(-9 * chunk_size),
+            ),
+        ];
+
+        let needed = needed_chunks(&grid, &regions, IVec3::splat(-1), UVec3::splat(5));
+
+        let mut expected = vec![
+            IVec3::new(0, 0, 0),
+            IVec3::new(2, 0, 0),
+            IVec3::new(3, 0, 0),
+        ];
+        let mut needed = needed.into_iter().collect::<Vec<_>>();
+        expected.sort_by_key(|chunk| chunk.to_array());
+        needed.sort_by_key(|chunk| chunk.to_array());
+
+        assert_eq!(needed, expected);
+    }
+}
@@ -0,0 +1,54 @@
+use crevice::std430::AsStd430;
+use glam::{IVec3, UVec3};
+
+use crate::game::world::{Material, CHUNK_SIZE};
+
+// Voxels index materials with a byte
+pub const MAX_MATERIALS: usize = 256;
+
+#[derive(Copy, Clone, Debug, AsStd430)]
+pub struct Uniforms {
+    chunk_size: i32,
+    window_origin: mint::Vector3<i32>, // In chunks
+    window_size: mint::Vector3<i32>,   // In chunks
+    pool_size: mint::Vector3<i32>,     // In chunk slots
+}
+
+impl Uniforms {
+    pub fn new(window_size: UVec3, pool_size: UVec3) -> Self {
+        Self {
+            chunk_size: CHUNK_SIZE as i32,
+            window_origin: IVec3::ZERO.into(),
+            window_size: window_size.as_ivec3().into(),
+            pool_size: pool_size.as_ivec3().into(),
+        }
+    }
+
+    pub fn update(&mut self, window_origin: IVec3) {
+        self.window_origin = window_origin.into();
+    }
+}
+
+// Mirrors Material in raytrace.frag, one per entry of the materials storage buffer
+#[derive(Copy, Clone, Debug, AsStd430)]
+pub struct GpuMaterial {
+    roughness: f32,
+    metalness: f32,
+    ior: f32,
+    transmission: f32, // The chance light passes into the voxel instead of reflecting off of it
+    emission: f32,     // Scales the voxel color into the light it gives off
+    density: f32,      // The extinction per voxel of smoke and clouds, zero for surfaces
+}
+
+impl From<&Material> for GpuMaterial {
+    fn from(material: &Material) -> Self {
+        Self {
+            roughness: material.roughness.clamp(0.0, 1.0),
+            metalness: material.metalness.clamp(0.0, 1.0),
+            ior: material.ior.max(1.0),
+            transmission: material.transmission.clamp(0.0, 1.0),
+            emission: material.emission,
+            density: material.density.max(0.0),
+        }
+    }
+}
@@ -3,7 +3,14 @@ use std::sync::Arc;
 use futures::lock::Mutex;
 use winit::event::ElementState;
 
-use crate::{config::ConfigValue, game::World, renderer::RenderContext};
+use crate::{
+    config::ConfigValue,
+    game::World,
+    renderer::{
+        raytracer::{DEBUG_VIEWS, GI_MODES, SKY_MODELS},
+        RenderContext,
+    },
+};
 
 use imgui::*;
 
@@ -11,10 +18,34 @@ pub struct UiState {
     // TODO make UiState use config values
     samples: i32,
     max_steps: i32,
+    max_bounces: i32,
+    roulette_depth: i32,
     reprojection_percent: f32,
     blur_strength: f32,
     move_speed: f32,
     do_lighting: bool,
+    distance_field: bool,
+    debug_view: usize, // The index of the view in DEBUG_VIEWS
+    gi_mode: usize,    // The index of the mode in GI_MODES
+    sun_direction: [f32; 3],
+    sun_color: [f32; 3],
+    sun_sharpness: f32,
+    sun_power: f32,
+    sun_time_of_day: bool,
+    sky_color: [f32; 3],
+    sky_power: f32,
+    sky_model: usize, // The index of the model in SKY_MODELS
+    sky_turbidity: f32,
+    sky_exposure: f32,
+    environment_intensity: f32,
+    environment_rotation: f32,
+    fog_density: f32,
+    fog_height: f32,
+    fog_height_falloff: f32,
+    fog_albedo: [f32; 3],
+    fog_anisotropy: f32,
+    time_of_day: f32,
+    time_of_day_edited: bool, // The clock runs on its own, so the time is only written back after the slider moved it
     world: Arc<Mutex<World>>,
 }
 
@@ -22,6 +53,13 @@ impl UiState {
     pub async fn new(world: Arc<Mutex<World>>) -> Self {
         let world_lock = world.lock().await;
         let config = world_lock.config.as_ref().unwrap();
+        let config_array = |name: &str, x: &str, y: &str, z: &str| {
+            [
+                config.get_var(&format!("{}_{}", name, x)).unwrap().as_f32(),
+                config.get_var(&format!("{}_{}", name, y)).unwrap().as_f32(),
+                config.get_var(&format!("{}_{}", name, z)).unwrap().as_f32(),
+            ]
+        };
 
         // todo make work from default config value
         UiState {
@@ -33,6 +71,14 @@ impl UiState {
                 .get_var("renderer_raytracer_max_steps")
                 .unwrap()
                 .as_i32(),
+            max_bounces: config
+                .get_var("renderer_raytracer_max_bounces")
+                .unwrap()
+                .as_i32(),
+            roulette_depth: config
+                .get_var("renderer_raytracer_roulette_depth")
+                .unwrap()
+                .as_i32(),
             reprojection_percent: config
                 .get_var("renderer_denoiser_reprojection_percent")
                 .unwrap()
@@ -49,11 +95,68 @@ impl UiState {
                 .get_var("renderer_raytracer_do_lighting")
                 .unwrap()
                 .as_bool(),
+            distance_field: config
+                .get_var("renderer_raytracer_acceleration")
+                .unwrap()
+                .as_string()
+                == "distance_field",
+            debug_view: DEBUG_VIEWS
+                .iter()
+                .position(|view| {
+                    *view == config.get_var("renderer_debug_view").unwrap().as_string()
+                })
+                .unwrap_or(0),
+            gi_mode: GI_MODES
+                .iter()
+                .position(|mode| {
+                    *mode
+                        == config
+                            .get_var("renderer_raytracer_gi_mode")
+                            .unwrap()
+                            .as_string()
+                })
+                .unwrap_or(0),
+            sun_direction: config_array("renderer_sun_direction", "x", "y", "z"),
+            sun_color: config_array("renderer_sun_color", "r", "g", "b"),
+            sun_sharpness: config.get_var("renderer_sun_sharpness").unwrap().as_f32(),
+            sun_power: config.get_var("renderer_sun_power").unwrap().as_f32(),
+            sun_time_of_day: config
+                .get_var("renderer_sun_time_of_day")
+                .unwrap()
+                .as_bool(),
+            sky_color: config_array("renderer_sky_color", "r", "g", "b"),
+            sky_power: config.get_var("renderer_sky_power").unwrap().as_f32(),
+            sky_model: SKY_MODELS
+                .iter()
+                .position(|model| {
+                    *model == config.get_var("renderer_sky_model").unwrap().as_string()
+                })
+                .unwrap_or(0),
+            sky_turbidity: config.get_var("renderer_sky_turbidity").unwrap().as_f32(),
+            sky_exposure: config.get_var("renderer_sky_exposure").unwrap().as_f32(),
+            environment_intensity: config
+                .get_var("renderer_environment_map_intensity")
+                .unwrap()
+                .as_f32(),
+            environment_rotation: config
+                .get_var("renderer_environment_map_rotation")
+                .unwrap()
+                .as_f32(),
+            fog_density: config.get_var("renderer_fog_density").unwrap().as_f32(),
+            fog_height: config.get_var("renderer_fog_height").unwrap().as_f32(),
+            fog_height_falloff: config
+                .get_var("renderer_fog_height_falloff")
+                .unwrap()
+                .as_f32(),
+            fog_albedo: config_array("renderer_fog_albedo", "r", "g", "b"),
+            fog_anisotropy: config.get_var("renderer_fog_anisotropy").unwrap().as_f32(),
+            time_of_day: config.get_var("game_world_time_of_day").unwrap().as_f32(),
+            time_of_day_edited: false,
             world: world.clone(),
         }
     }
 
-    pub async fn update(&self) {
+    pub async fn update(&mut self) {
         let mut world_lock = self.world.lock().await;
         let config = world_lock.config.as_mut().unwrap();
 
@@ -63,6 +166,14 @@ impl UiState {
             "renderer_raytracer_max_steps",
             ConfigValue::I32(self.max_steps),
         );
+        config.set_var(
+            "renderer_raytracer_max_bounces",
+            ConfigValue::I32(self.max_bounces),
+        );
+        config.set_var(
+            "renderer_raytracer_roulette_depth",
+            ConfigValue::I32(self.roulette_depth),
+        );
         config.set_var(
             "renderer_denoiser_reprojection_percent",
             ConfigValue::F32(self.reprojection_percent),
@@ -79,6 +190,83 @@ impl UiState {
             "renderer_raytracer_do_lighting",
             ConfigValue::Bool(self.do_lighting),
         );
+        config.set_var(
+            "renderer_raytracer_acceleration",
+            ConfigValue::String(
+                if self.distance_field {
+                    "distance_field"
+                } else {
+                    "octree"
+                }
+                .to_string(),
+            ),
+        );
+        config.set_var(
+            "renderer_debug_view",
+            ConfigValue::String(DEBUG_VIEWS[self.debug_view].to_string()),
+        );
+        config.set_var(
+            "renderer_raytracer_gi_mode",
+            ConfigValue::String(GI_MODES[self.gi_mode].to_string()),
+        );
+
+        for (name, components, values) in [
+            (
+                "renderer_sun_direction",
+                ["x", "y", "z"],
+                self.sun_direction,
+            ),
+            ("renderer_sun_color", ["r", "g", "b"], self.sun_color),
+            ("renderer_sky_color", ["r", "g", "b"], self.sky_color),
+            ("renderer_fog_albedo", ["r", "g", "b"], self.fog_albedo),
+        ] {
+            for (component, value) in components.iter().zip(values) {
+                config.set_var(&format!("{}_{}", name, component), ConfigValue::F32(value));
+            }
+        }
+
+        config.set_var(
+            "renderer_sun_sharpness",
+            ConfigValue::F32(self.sun_sharpness),
+        );
+        config.set_var("renderer_sun_power", ConfigValue::F32(self.sun_power));
+        config.set_var(
+            "renderer_sun_time_of_day",
+            ConfigValue::Bool(self.sun_time_of_day),
+        );
+        config.set_var("renderer_sky_power", ConfigValue::F32(self.sky_power));
+        config.set_var(
+            "renderer_sky_model",
+            ConfigValue::String(SKY_MODELS[self.sky_model].to_string()),
+        );
+        config.set_var(
+            "renderer_sky_turbidity",
+            ConfigValue::F32(self.sky_turbidity),
+        );
+        config.set_var("renderer_sky_exposure", ConfigValue::F32(self.sky_exposure));
+        config.set_var(
+            "renderer_environment_map_intensity",
+            ConfigValue::F32(self.environment_intensity),
+        );
+        config.set_var(
+            "renderer_environment_map_rotation",
+            ConfigValue::F32(self.environment_rotation),
+        );
+        config.set_var("renderer_fog_density", ConfigValue::F32(self.fog_density));
+        config.set_var("renderer_fog_height", ConfigValue::F32(self.fog_height));
+        config.set_var(
+            "renderer_fog_height_falloff",
+            ConfigValue::F32(self.fog_height_falloff),
+        );
+        config.set_var(
+            "renderer_fog_anisotropy",
+            ConfigValue::F32(self.fog_anisotropy),
+        );
+
+        if self.time_of_day_edited {
+            self.time_of_day_edited = false;
+            config.set_var("game_world_time_of_day", ConfigValue::F32(self.time_of_day));
+        }
     }
 }
 
@@ -137,6 +325,7 @@ impl Ui {
     ) {
         let mut world_lock = world.lock().await;
         let config = world_lock.config.as_ref().unwrap();
+        let time_of_day = config.get_var("game_world_time_of_day").unwrap().as_f32();
         let gui = world_lock.ui.as_mut().unwrap();
         let ui = gui.context.frame();
         let window = imgui::Window::new("Render Stats");
@@ -146,10 +335,16 @@ impl Ui {
             .build(&ui, || {
                 ui.text(format!("Frame Time: {}", context.frame_time));
                 ui.text(format!("Render Time: {}", context.render_time));
+                match context.mipmap_time {
+                    Some(time) => ui.text(format!("Mipmap Time: {}", time)),
+                    None => ui.text("Mipmap Time: needs timestamp queries"),
+                }
                 ui.separator();
                 ui.text("Raytracer Config");
                 Slider::new("Samples", 1, 20).build(&ui, &mut ui_state.samples);
                 Slider::new("Max Steps", 1, 250).build(&ui, &mut ui_state.max_steps);
+                Slider::new("Max Bounces", 0, 16).build(&ui, &mut ui_state.max_bounces);
+                Slider::new("Roulette Depth", 0, 16).build(&ui, &mut ui_state.roulette_depth);
                 Slider::new("Reprojection Percent", 0.5f32, 1.0)
                     .build(&ui, &mut ui_state.reprojection_percent);
                 Slider::new("Edge Avoiding Blur Strength", 0.0f32, 2.5)
@@ -159,6 +354,43 @@ impl Ui {
                 if ui.radio_button_bool("disable lighting", ui_state.do_lighting) {
                     ui_state.do_lighting = !ui_state.do_lighting;
                 }
+
+                ui.checkbox("Distance Field Acceleration", &mut ui_state.distance_field);
+                ui.combo_simple_string("Debug View", &mut ui_state.debug_view, &DEBUG_VIEWS);
+                ui.combo_simple_string("GI Mode", &mut ui_state.gi_mode, &GI_MODES);
+
+                ui.separator();
+                ui.text("Lighting");
+                Slider::new("Sun Direction", -1.0f32, 1.0)
+                    .build_array(&ui, &mut ui_state.sun_direction);
+                ColorEdit::new("Sun Color", &mut ui_state.sun_color).build(&ui);
+                Slider::new("Sun Sharpness", 1.0f32, 64.0).build(&ui, &mut ui_state.sun_sharpness);
+                Slider::new("Sun Power", 0.0f32, 16.0).build(&ui, &mut ui_state.sun_power);
+                ColorEdit::new("Sky Color", &mut ui_state.sky_color).build(&ui);
+                Slider::new("Sky Power", 0.0f32, 8.0).build(&ui, &mut ui_state.sky_power);
+                ui.combo_simple_string("Sky Model", &mut ui_state.sky_model, &SKY_MODELS);
+                Slider::new("Sky Turbidity", 2.0f32, 10.0).build(&ui, &mut ui_state.sky_turbidity);
+                Slider::new("Sky Exposure", 0.0f32, 0.1).build(&ui, &mut ui_state.sky_exposure);
+                Slider::new("Environment Intensity", 0.0f32, 8.0)
+                    .build(&ui, &mut ui_state.environment_intensity);
+                Slider::new("Environment Rotation", 0.0f32, 360.0)
+                    .build(&ui, &mut ui_state.environment_rotation);
+                ui.checkbox("Time Of Day", &mut ui_state.sun_time_of_day);
+
+                // Show the running clock, the hour is only written back when the slider moved it
+                ui_state.time_of_day = time_of_day;
+                ui_state.time_of_day_edited =
+                    Slider::new("Hour", 0.0f32, 24.0).build(&ui, &mut ui_state.time_of_day);
+
+                ui.separator();
+                ui.text("Fog");
+                Slider::new("Fog Density", 0.0f32, 0.5).build(&ui, &mut ui_state.fog_density);
+                Slider::new("Fog Height", -100.0f32, 100.0).build(&ui, &mut ui_state.fog_height);
+                Slider::new("Fog Height Falloff", 0.0f32, 1.0)
+                    .build(&ui, &mut ui_state.fog_height_falloff);
+                ColorEdit::new("Fog Albedo", &mut ui_state.fog_albedo).build(&ui);
+                Slider::new("Fog Anisotropy", -0.9f32, 0.9)
+                    .build(&ui, &mut ui_state.fog_anisotropy);
             });
 
         gui.platform.prepare_render(&ui, &context.window);